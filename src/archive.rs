@@ -0,0 +1,260 @@
+// Download archive: an append-only file with one line per downloaded track, so a track is
+// skipped by its TIDAL id and quality however its file was renamed, moved or retagged, and
+// a track in several playlists is only downloaded once.
+//
+// Lines are tab separated: track id, quality, size in bytes, unix timestamp, path. The path
+// comes last since it is the only field that may contain a tab. A later line for the same
+// track and quality replaces an earlier one.
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lofty::{
+    mp4::{AtomData, AtomIdent, Mp4Codec, Mp4File},
+    AudioFile, FileType, ItemKey, ParseOptions, Probe, TaggedFileExt,
+};
+
+use crate::error::{Error, Result};
+use crate::loudness;
+
+// Tag downloaded tracks carry their TIDAL id in, which `import` reads back
+pub const TRACK_ID_TAG: &str = "TIDAL_TRACK_ID";
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub track_id: u32,
+    // The audio_quality the track was requested in (LOW, HIGH, LOSSLESS or HI_RES)
+    pub quality: String,
+    pub size: u64,
+    pub timestamp: u64,
+    pub path: PathBuf,
+}
+
+impl Entry {
+    pub fn new(track_id: u32, quality: &str, path: &Path) -> Result<Entry> {
+        Ok(Entry {
+            track_id,
+            quality: quality.to_string(),
+            size: fs::metadata(path)?.len(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn parse(line: &str) -> Option<Entry> {
+        let mut fields = line.splitn(5, '\t');
+        Some(Entry {
+            track_id: fields.next()?.parse().ok()?,
+            quality: fields.next()?.to_string(),
+            size: fields.next()?.parse().ok()?,
+            timestamp: fields.next()?.parse().ok()?,
+            path: PathBuf::from(fields.next()?),
+        })
+    }
+
+    fn line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            self.track_id,
+            self.quality,
+            self.size,
+            self.timestamp,
+            self.path.display()
+        )
+    }
+}
+
+type Key = (u32, String);
+
+#[derive(Debug)]
+pub struct Archive {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<Key, Entry>,
+    // Tracks being downloaded right now, so concurrent urls sharing a track fetch it once
+    claimed: HashSet<Key>,
+}
+
+impl Archive {
+    // A missing archive file is created on the first download
+    pub fn open(path: &Path) -> Result<Archive> {
+        let mut entries = HashMap::new();
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                for (number, line) in content.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    // A line cut short by a crash is not worth failing the whole run for
+                    match Entry::parse(line) {
+                        Some(entry) => {
+                            entries.insert((entry.track_id, entry.quality.clone()), entry);
+                        }
+                        None => eprintln!(
+                            "{}:{}: ignoring malformed archive line",
+                            path.display(),
+                            number + 1
+                        ),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Archive {
+            path: path.to_path_buf(),
+            state: Mutex::new(State {
+                entries,
+                claimed: HashSet::new(),
+            }),
+        })
+    }
+
+    // Whether the track still has to be downloaded: false when it is in the archive or
+    // another download has claimed it. A successful claim ends with `record` or `release`.
+    pub fn try_claim(&self, track_id: u32, quality: &str) -> bool {
+        let key = (track_id, quality.to_string());
+        let mut state = self.state.lock().unwrap();
+        !state.entries.contains_key(&key) && state.claimed.insert(key)
+    }
+
+    // Give up the claim of a download that failed, a later url or run may try again
+    pub fn release(&self, track_id: u32, quality: &str) {
+        let key = (track_id, quality.to_string());
+        self.state.lock().unwrap().claimed.remove(&key);
+    }
+
+    pub fn record(&self, entry: Entry) -> Result<()> {
+        // Appending under the lock keeps lines of concurrent downloads from interleaving
+        let mut state = self.state.lock().unwrap();
+        let key = (entry.track_id, entry.quality.clone());
+        state.claimed.remove(&key);
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(entry.line().as_bytes())?;
+        state.entries.insert(key, entry);
+        Ok(())
+    }
+
+    // Entries sorted by path
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries: Vec<Entry> =
+            self.state.lock().unwrap().entries.values().cloned().collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries
+    }
+
+    // Drop the entries whose file no longer exists and rewrite the archive without them or
+    // replaced lines. Returns the dropped entries.
+    pub fn prune(&self) -> Result<Vec<Entry>> {
+        let entries = &mut self.state.lock().unwrap().entries;
+        let (mut kept, pruned): (Vec<Entry>, Vec<Entry>) =
+            entries.values().cloned().partition(|entry| entry.path.exists());
+        kept.sort_by(|a, b| a.path.cmp(&b.path));
+        // Write a copy and rename it over the archive, an interruption keeps the old one
+        let tmp_path = PathBuf::from(format!("{}.tmp", self.path.display()));
+        let mut file = File::create(&tmp_path)?;
+        for entry in &kept {
+            file.write_all(entry.line().as_bytes())?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        entries.retain(|_, entry| entry.path.exists());
+        Ok(pruned)
+    }
+
+    // Record every FLAC/M4A file below `folder` that has a TIDAL_TRACK_ID tag. Files are
+    // recorded in `quality` if given, otherwise in the quality they actually are. Returns how
+    // many files were recorded and the files that were skipped, with the reason.
+    pub fn import(
+        &self,
+        folder: &Path,
+        quality: Option<&str>,
+    ) -> Result<(usize, Vec<(PathBuf, Error)>)> {
+        let mut albums = BTreeMap::new();
+        loudness::collect_files(folder, &mut albums)?;
+        let mut files: Vec<PathBuf> = albums.into_values().flatten().collect();
+        files.sort();
+        let mut imported = 0;
+        let mut skipped = Vec::new();
+        for path in files {
+            let result = read_file(&path).and_then(|(track_id, file_quality)| {
+                let track_id = track_id.ok_or_else(|| {
+                    Error::Unsupported(format!("no {} tag", TRACK_ID_TAG))
+                })?;
+                let quality = quality.unwrap_or(file_quality);
+                self.record(Entry::new(track_id, quality, &path)?)
+            });
+            match result {
+                Ok(()) => imported += 1,
+                Err(e) => skipped.push((path, e)),
+            }
+        }
+        Ok((imported, skipped))
+    }
+}
+
+// The TIDAL id from the tags and the audio_quality the file corresponds to: AAC is LOW below
+// 200 kbps and HIGH above, lossless files are HI_RES beyond 16 bit / 48 kHz
+fn read_file(path: &Path) -> Result<(Option<u32>, &'static str)> {
+    let lossless = |bit_depth: Option<u8>, sample_rate: Option<u32>| {
+        if bit_depth.unwrap_or(16) > 16 || sample_rate.unwrap_or(44100) > 48000 {
+            "HI_RES"
+        } else {
+            "LOSSLESS"
+        }
+    };
+    let (value, quality) = if Probe::open(path)?.guess_file_type()?.file_type()
+        == Some(FileType::Mp4)
+    {
+        // Freeform atoms have no generic ItemKey, look them up in the ilst
+        let mut file = File::open(path)?;
+        let mp4 = Mp4File::read_from(&mut file, ParseOptions::new())?;
+        let ident = AtomIdent::Freeform {
+            mean: Cow::Borrowed("com.apple.iTunes"),
+            name: Cow::Borrowed(TRACK_ID_TAG),
+        };
+        let value = mp4
+            .ilst()
+            .and_then(|ilst| ilst.get(&ident))
+            .and_then(|atom| atom.data().next())
+            .and_then(|data| match data {
+                AtomData::UTF8(value) => Some(value.clone()),
+                _ => None,
+            });
+        let properties = mp4.properties();
+        let quality = match properties.codec() {
+            Mp4Codec::AAC | Mp4Codec::MP3 if properties.audio_bitrate() < 200 => "LOW",
+            Mp4Codec::AAC | Mp4Codec::MP3 => "HIGH",
+            Mp4Codec::FLAC | Mp4Codec::ALAC => {
+                lossless(properties.bit_depth(), Some(properties.sample_rate()))
+            }
+            _ => {
+                return Err(Error::Unsupported(String::from("unknown MP4 audio codec")));
+            }
+        };
+        (value, quality)
+    } else {
+        let tagged_file = Probe::open(path)?.read()?;
+        let key = ItemKey::Unknown(TRACK_ID_TAG.to_string());
+        let value = tagged_file
+            .primary_tag()
+            .and_then(|tag| tag.get_string(&key))
+            .map(|value| value.to_string());
+        let properties = tagged_file.properties();
+        (value, lossless(properties.bit_depth(), properties.sample_rate()))
+    };
+    Ok((value.and_then(|value| value.trim().parse().ok()), quality))
+}
@@ -1,9 +1,46 @@
-#[derive(Debug)]
+use crate::cover::COVER_SIZES;
+use crate::error;
+use crate::sanitize::Sanitizer;
+use crate::throttle;
+use crate::template::{Template, PLAYLIST_FIELDS, TRACK_FIELDS, VIDEO_FIELDS};
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub download_path: String,
     pub audio_quality: AudioQuality,
     pub save_cover: bool,
+    pub embed_cover: bool,
+    pub embed_cover_size: String,
+    pub folder_cover_size: String,
     pub exist_check: bool,
+    pub artist_albums: bool,
+    pub artist_eps_singles: bool,
+    pub artist_compilations: bool,
+    pub album_template: String,
+    pub playlist_template: String,
+    pub track_template: String,
+    pub video_template: String,
+    pub video_resolution: u32,
+    pub download_videos: bool,
+    pub replacement_char: String,
+    pub max_name_length: usize,
+    pub windows_compatible: bool,
+    pub disc_folders: bool,
+    pub replay_gain: bool,
+    pub loudness_analysis: LoudnessAnalysis,
+    pub embed_lyrics: bool,
+    pub save_lrc: bool,
+    pub credits: bool,
+    pub retry_attempts: u32,
+    pub retry_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    pub connect_timeout_ms: u64,
+    pub response_timeout_ms: u64,
+    pub max_downloads: usize,
+    pub max_api_requests: usize,
+    pub max_bandwidth: String,
+    pub bandwidth_schedule: String,
+    pub download_archive: String,
 }
 
 impl Config {
@@ -12,12 +49,81 @@ impl Config {
             download_path: String::from("./"),
             audio_quality: AudioQuality::LOSSLESS,
             save_cover: true,
+            embed_cover: true,
+            embed_cover_size: String::from("1280"),
+            folder_cover_size: String::from("1280"),
             exist_check: true,
+            artist_albums: true,
+            artist_eps_singles: true,
+            artist_compilations: false,
+            album_template: String::from("Album/{artist}/{album}"),
+            playlist_template: String::from("Playlist/{playlist}"),
+            track_template: String::from("{artist} - {title}"),
+            video_template: String::from("Video/{artist} - {title}"),
+            video_resolution: 1080,
+            download_videos: false,
+            replacement_char: String::from("_"),
+            max_name_length: 200,
+            windows_compatible: true,
+            disc_folders: false,
+            replay_gain: true,
+            loudness_analysis: LoudnessAnalysis::Off,
+            embed_lyrics: true,
+            save_lrc: true,
+            credits: true,
+            retry_attempts: 5,
+            retry_delay_ms: 1000,
+            retry_max_delay_ms: 60000,
+            connect_timeout_ms: 10000,
+            response_timeout_ms: 30000,
+            max_downloads: 4,
+            max_api_requests: 4,
+            max_bandwidth: String::from("0"),
+            bandwidth_schedule: String::new(),
+            download_archive: String::new(),
+        }
+    }
+
+    // Catch template mistakes when the config is loaded instead of halfway through a download
+    pub fn validate(&self) -> error::Result<()> {
+        Template::parse(&self.album_template, "album_template", TRACK_FIELDS)?;
+        Template::parse(&self.playlist_template, "playlist_template", PLAYLIST_FIELDS)?;
+        Template::parse(&self.track_template, "track_template", TRACK_FIELDS)?;
+        Template::parse(&self.video_template, "video_template", VIDEO_FIELDS)?;
+        Sanitizer::validate(self)?;
+        throttle::parse_rate(&self.max_bandwidth)?;
+        throttle::parse_schedule(&self.bandwidth_schedule)?;
+        for (option, size) in [
+            ("embed_cover_size", &self.embed_cover_size),
+            ("folder_cover_size", &self.folder_cover_size),
+        ] {
+            if !COVER_SIZES.contains(&size.as_str()) {
+                return Err(error::Error::Config(format!(
+                    "{} \"{}\" is not one of {}",
+                    option,
+                    size,
+                    COVER_SIZES.join(", ")
+                )));
+            }
+        }
+        for (option, value) in [
+            ("retry_attempts", self.retry_attempts as usize),
+            ("connect_timeout_ms", self.connect_timeout_ms as usize),
+            ("response_timeout_ms", self.response_timeout_ms as usize),
+            ("max_downloads", self.max_downloads),
+            ("max_api_requests", self.max_api_requests),
+        ] {
+            if value == 0 {
+                return Err(error::Error::Config(format!("{} must be at least 1", option)));
+            }
         }
+        Ok(())
     }
 }
 
-#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
+// Variant names are written as-is to .tdlrs.json
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
 pub enum AudioQuality {
     LOW,
     HIGH,
@@ -25,6 +131,18 @@ pub enum AudioQuality {
     MASTER,
 }
 
+impl AudioQuality {
+    // The name the playback info endpoints take
+    pub fn api_name(&self) -> &'static str {
+        match self {
+            AudioQuality::LOW => "LOW",
+            AudioQuality::HIGH => "HIGH",
+            AudioQuality::LOSSLESS => "LOSSLESS",
+            AudioQuality::MASTER => "HI_RES",
+        }
+    }
+}
+
 impl std::str::FromStr for AudioQuality {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -38,4 +156,36 @@ impl std::str::FromStr for AudioQuality {
     }
 }
 
+// When to measure loudness locally instead of trusting TIDAL's ReplayGain values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoudnessAnalysis {
+    Off,
+    // Only albums where TIDAL's track or album values are missing
+    Missing,
+    Always,
+}
+
+impl std::str::FromStr for LoudnessAnalysis {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(LoudnessAnalysis::Off),
+            "missing" => Ok(LoudnessAnalysis::Missing),
+            "always" => Ok(LoudnessAnalysis::Always),
+            _ => Err(format!("invalid loudness_analysis: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for LoudnessAnalysis {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let value = match self {
+            LoudnessAnalysis::Off => "off",
+            LoudnessAnalysis::Missing => "missing",
+            LoudnessAnalysis::Always => "always",
+        };
+        f.write_str(value)
+    }
+}
+
 // Download path, Audio quality, Save covers
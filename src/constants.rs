@@ -3,5 +3,4 @@ pub const TIDAL_CLIENT_INFO_SECRET: &str =
     "VkpLaERGcUpQcXZzUFZOQlY2dWtYVEptd2x2YnR0UDd3bE1scmM3MnNlND0=";
 pub const TIDAL_BASE: &str = "https://api.tidal.com/v1";
 pub const TIDAL_AUTH_LINK: &str = "https://auth.tidal.com/v1/oauth2";
-pub const TEST_DL_LINK: &str = "https://tidal.com/browse/track/77706588";
 pub const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0.4472.124 Safari/537.36 Edg/91.0.864.59";
@@ -0,0 +1,83 @@
+// Cover art and other images from resources.tidal.com. Every track of an album shares one
+// cover, so covers are cached for the whole download run and each size is fetched only once.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use reqwest::{Client, StatusCode};
+use tokio::sync::OnceCell;
+
+use crate::error::{Error, Result};
+use crate::retry::RetryPolicy;
+
+// Square sizes TIDAL serves album covers in, besides the uploaded original
+pub const COVER_SIZES: &[&str] = &["80", "160", "320", "640", "750", "1080", "1280", "origin"];
+
+// Artist pictures and playlist images come in other sizes than album covers
+pub const ARTIST_PICTURE_SIZE: &str = "750x750";
+pub const PLAYLIST_SQUARE_IMAGE_SIZE: &str = "1080x1080";
+pub const PLAYLIST_IMAGE_SIZE: &str = "1080x720";
+
+// `image_id` is the UUID from the API (album `cover`, artist `picture`, playlist `image`...),
+// `size` either WIDTHxHEIGHT or "origin"
+pub fn resource_url(image_id: &str, size: &str) -> String {
+    format!(
+        "https://resources.tidal.com/images/{}/{}.jpg",
+        image_id.replace('-', "/"),
+        size
+    )
+}
+
+// `size` is one of COVER_SIZES
+pub fn cover_url(cover_id: &str, size: &str) -> String {
+    if size == "origin" {
+        resource_url(cover_id, size)
+    } else {
+        resource_url(cover_id, &format!("{}x{}", size, size))
+    }
+}
+
+// Keyed by cover url, so every size of a cover has its own entry
+type Covers = HashMap<String, Arc<OnceCell<Vec<u8>>>>;
+
+#[derive(Debug, Clone)]
+pub struct CoverCache {
+    covers: Arc<Mutex<Covers>>,
+    retry: RetryPolicy,
+}
+
+impl CoverCache {
+    pub fn new(retry: RetryPolicy) -> CoverCache {
+        CoverCache {
+            covers: Default::default(),
+            retry,
+        }
+    }
+
+    // Concurrent requests for the same cover wait for the first one instead of fetching it
+    // again. A failed fetch isn't cached, the next track tries again.
+    pub async fn get(&self, client: &Client, cover_id: &str, size: &str) -> Result<Vec<u8>> {
+        let url = cover_url(cover_id, size);
+        let cell = self
+            .covers
+            .lock()
+            .unwrap()
+            .entry(url.clone())
+            .or_default()
+            .clone();
+        let cover = cell
+            .get_or_try_init(|| async {
+                let resp = self.retry.send(client.get(&url)).await?;
+                match resp.status() {
+                    StatusCode::OK => Ok(resp.bytes().await?.to_vec()),
+                    status => Err(Error::Http {
+                        status,
+                        url: url.clone(),
+                    }),
+                }
+            })
+            .await?;
+        Ok(cover.clone())
+    }
+}
@@ -0,0 +1,399 @@
+// Minimal MPEG-DASH (application/dash+xml) manifest support, enough for the static
+// SegmentTemplate + SegmentTimeline manifests TIDAL returns for HI_RES and some AAC streams.
+use reqwest::Url;
+use roxmltree::{Document, Node};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug)]
+pub struct DashStream {
+    pub mime_type: String,
+    pub codecs: String,
+    // Initialization segment followed by every media segment, in playback order
+    pub urls: Vec<String>,
+}
+
+pub fn parse_mpd(xml: &str) -> Result<DashStream> {
+    let doc = Document::parse(xml).map_err(|e| Error::Manifest(e.to_string()))?;
+    let mpd = doc.root_element();
+    if descendants(mpd, "ContentProtection").next().is_some() {
+        return Err(Error::Unsupported(String::from("DRM protected DASH stream")));
+    }
+    let duration = mpd
+        .attribute("mediaPresentationDuration")
+        .and_then(parse_duration);
+
+    // Pick the best representation of the first audio adaptation set
+    let adaptation_set = descendants(mpd, "AdaptationSet")
+        .find(|set| {
+            set.attribute("contentType") == Some("audio")
+                || set.attribute("mimeType").unwrap_or("").starts_with("audio")
+        })
+        .or_else(|| descendants(mpd, "AdaptationSet").next())
+        .ok_or_else(|| Error::Manifest(String::from("no AdaptationSet in MPD")))?;
+    let representation = children(adaptation_set, "Representation")
+        .max_by_key(|r| {
+            r.attribute("bandwidth")
+                .and_then(|b| b.parse::<u64>().ok())
+                .unwrap_or(0)
+        })
+        .ok_or_else(|| Error::Manifest(String::from("no Representation in MPD")))?;
+
+    let template = children(representation, "SegmentTemplate")
+        .next()
+        .or_else(|| children(adaptation_set, "SegmentTemplate").next())
+        .ok_or_else(|| Error::Manifest(String::from("no SegmentTemplate in MPD")))?;
+
+    let base_url = base_url(representation);
+    let resolve = |url: &str| -> Result<String> {
+        match &base_url {
+            Some(base) => base
+                .join(url)
+                .map(|u| u.to_string())
+                .map_err(|e| Error::Manifest(e.to_string())),
+            None => Ok(url.to_string()),
+        }
+    };
+    let vars = TemplateVars {
+        representation_id: representation.attribute("id").unwrap_or(""),
+        bandwidth: representation.attribute("bandwidth").unwrap_or(""),
+    };
+
+    let mut urls = Vec::new();
+    if let Some(init) = template.attribute("initialization") {
+        urls.push(resolve(&vars.expand(init, 0, 0)?)?);
+    }
+    let media = template
+        .attribute("media")
+        .ok_or_else(|| Error::Manifest(String::from("SegmentTemplate has no media")))?;
+    let timescale = attr_u64(template, "timescale").unwrap_or(1).max(1);
+    let mut number = attr_u64(template, "startNumber").unwrap_or(1);
+
+    match children(template, "SegmentTimeline").next() {
+        Some(timeline) => {
+            let segments: Vec<Node> = children(timeline, "S").collect();
+            let mut time = 0;
+            for (i, segment) in segments.iter().enumerate() {
+                let d = attr_u64(*segment, "d")
+                    .filter(|d| *d > 0)
+                    .ok_or_else(|| Error::Manifest(String::from("S element without d")))?;
+                if let Some(t) = attr_u64(*segment, "t") {
+                    time = t;
+                }
+                let r = segment
+                    .attribute("r")
+                    .and_then(|r| r.parse::<i64>().ok())
+                    .unwrap_or(0);
+                // r="-1" repeats the segment until the next S element or the end of the period
+                let repeat = if r >= 0 {
+                    r as u64
+                } else {
+                    let end = match segments.get(i + 1).and_then(|next| attr_u64(*next, "t")) {
+                        Some(next_t) => next_t,
+                        None => {
+                            let duration = duration.ok_or_else(|| {
+                                Error::Manifest(String::from(
+                                    "open-ended SegmentTimeline without mediaPresentationDuration",
+                                ))
+                            })?;
+                            (duration * timescale as f64).ceil() as u64
+                        }
+                    };
+                    end.saturating_sub(time).div_ceil(d).saturating_sub(1)
+                };
+                for _ in 0..=repeat {
+                    urls.push(resolve(&vars.expand(media, number, time)?)?);
+                    number += 1;
+                    time += d;
+                }
+            }
+        }
+        None => {
+            // Fixed-duration segments
+            let d = attr_u64(template, "duration").filter(|d| *d > 0).ok_or_else(|| {
+                Error::Manifest(String::from("SegmentTemplate without duration or timeline"))
+            })?;
+            let duration = duration.ok_or_else(|| {
+                Error::Manifest(String::from("MPD without mediaPresentationDuration"))
+            })?;
+            let count = (duration * timescale as f64 / d as f64).ceil() as u64;
+            for i in 0..count {
+                urls.push(resolve(&vars.expand(media, number, i * d)?)?);
+                number += 1;
+            }
+        }
+    }
+
+    Ok(DashStream {
+        mime_type: attr_inherited(representation, "mimeType").unwrap_or_default(),
+        codecs: attr_inherited(representation, "codecs").unwrap_or_default(),
+        urls,
+    })
+}
+
+struct TemplateVars<'a> {
+    representation_id: &'a str,
+    bandwidth: &'a str,
+}
+
+impl TemplateVars<'_> {
+    // Expand $Number$, $Time$, $RepresentationID$ and $Bandwidth$, including the
+    // printf-style width form such as $Number%05d$
+    fn expand(&self, template: &str, number: u64, time: u64) -> Result<String> {
+        let mut out = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('$') {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let end = after
+                .find('$')
+                .ok_or_else(|| Error::Manifest(format!("unterminated $ in {}", template)))?;
+            let ident = &after[..end];
+            let (name, format) = match ident.split_once('%') {
+                Some((name, format)) => (name, Some(format)),
+                None => (ident, None),
+            };
+            let value = match name {
+                "" => String::from("$"),
+                "Number" => number.to_string(),
+                "Time" => time.to_string(),
+                "RepresentationID" => self.representation_id.to_string(),
+                "Bandwidth" => self.bandwidth.to_string(),
+                _ => {
+                    return Err(Error::Manifest(format!(
+                        "unknown template identifier ${}$",
+                        ident
+                    )))
+                }
+            };
+            match format {
+                Some(format) => {
+                    let width = format
+                        .trim_start_matches('0')
+                        .trim_end_matches('d')
+                        .parse::<usize>()
+                        .unwrap_or(0);
+                    out.push_str(&format!("{:0>width$}", value, width = width));
+                }
+                None => out.push_str(&value),
+            }
+            rest = &after[end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn descendants<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.descendants()
+        .filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn attr_u64(node: Node, name: &str) -> Option<u64> {
+    node.attribute(name).and_then(|v| v.parse::<u64>().ok())
+}
+
+// Attributes such as mimeType and codecs may be set on the Representation or its AdaptationSet
+fn attr_inherited(node: Node, name: &str) -> Option<String> {
+    node.ancestors()
+        .filter(|n| n.is_element())
+        .find_map(|n| n.attribute(name))
+        .map(|v| v.to_string())
+}
+
+// Combine every BaseURL from the MPD root down to the representation
+fn base_url(node: Node) -> Option<Url> {
+    let mut levels: Vec<Node> = node.ancestors().filter(|n| n.is_element()).collect();
+    levels.reverse();
+    let mut base: Option<Url> = None;
+    for level in levels {
+        if let Some(text) = children(level, "BaseURL").next().and_then(|b| b.text()) {
+            let text = text.trim();
+            base = match &base {
+                Some(base) => base.join(text).ok(),
+                None => Url::parse(text).ok(),
+            };
+        }
+    }
+    base
+}
+
+// ISO 8601 durations as used by mediaPresentationDuration, e.g. PT3M46.453S
+fn parse_duration(value: &str) -> Option<f64> {
+    let value = value.strip_prefix('P')?;
+    let (days, time) = match value.split_once('T') {
+        Some((days, time)) => (days, time),
+        None => (value, ""),
+    };
+    let mut seconds = 0.0;
+    if let Some(days) = days.strip_suffix('D') {
+        seconds += days.parse::<f64>().ok()? * 86400.0;
+    }
+    let mut number = String::new();
+    for c in time.chars() {
+        match c {
+            'H' => seconds += number.parse::<f64>().ok()? * 3600.0,
+            'M' => seconds += number.parse::<f64>().ok()? * 60.0,
+            'S' => seconds += number.parse::<f64>().ok()?,
+            _ => {
+                number.push(c);
+                continue;
+            }
+        }
+        number.clear();
+    }
+    Some(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mpd(duration: &str, base_urls: (&str, &str, &str), template: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="{}">
+  <BaseURL>{}</BaseURL>
+  <Period>
+    <BaseURL>{}</BaseURL>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4">
+      <Representation id="FLAC,44100,16" codecs="flac" bandwidth="1000">
+        <BaseURL>{}</BaseURL>
+        {}
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#,
+            duration, base_urls.0, base_urls.1, base_urls.2, template
+        )
+    }
+
+    const BASE: (&str, &str, &str) = ("https://example.com/", "", "");
+
+    #[test]
+    fn timeline_repeats() {
+        let xml = mpd(
+            "PT10S",
+            BASE,
+            r#"<SegmentTemplate timescale="100" initialization="init.mp4" media="$Number$-$Time$.mp4" startNumber="1">
+                 <SegmentTimeline><S d="400" r="1"/><S d="200"/></SegmentTimeline>
+               </SegmentTemplate>"#,
+        );
+        let stream = parse_mpd(&xml).unwrap();
+        assert_eq!(stream.mime_type, "audio/mp4");
+        assert_eq!(stream.codecs, "flac");
+        assert_eq!(
+            stream.urls,
+            [
+                "https://example.com/init.mp4",
+                "https://example.com/1-0.mp4",
+                "https://example.com/2-400.mp4",
+                "https://example.com/3-800.mp4",
+            ]
+        );
+    }
+
+    #[test]
+    fn open_ended_repeat_runs_to_duration() {
+        // 9.5 s of 4 s segments is three segments, the last one shorter
+        let xml = mpd(
+            "PT9.5S",
+            BASE,
+            r#"<SegmentTemplate timescale="1000" media="$Number$.mp4" startNumber="0">
+                 <SegmentTimeline><S t="0" d="4000" r="-1"/></SegmentTimeline>
+               </SegmentTemplate>"#,
+        );
+        let stream = parse_mpd(&xml).unwrap();
+        assert_eq!(
+            stream.urls,
+            [
+                "https://example.com/0.mp4",
+                "https://example.com/1.mp4",
+                "https://example.com/2.mp4",
+            ]
+        );
+    }
+
+    #[test]
+    fn open_ended_repeat_runs_to_next_segment() {
+        let xml = mpd(
+            "PT10S",
+            BASE,
+            r#"<SegmentTemplate media="$Time$.mp4">
+                 <SegmentTimeline><S t="0" d="2" r="-1"/><S t="6" d="4"/></SegmentTimeline>
+               </SegmentTemplate>"#,
+        );
+        let stream = parse_mpd(&xml).unwrap();
+        assert_eq!(
+            stream.urls,
+            [
+                "https://example.com/0.mp4",
+                "https://example.com/2.mp4",
+                "https://example.com/4.mp4",
+                "https://example.com/6.mp4",
+            ]
+        );
+    }
+
+    #[test]
+    fn open_ended_repeat_needs_duration() {
+        let xml = r#"<MPD><Period><AdaptationSet contentType="audio"><Representation>
+              <SegmentTemplate media="$Number$.mp4">
+                <SegmentTimeline><S d="10" r="-1"/></SegmentTimeline>
+              </SegmentTemplate>
+            </Representation></AdaptationSet></Period></MPD>"#;
+        assert!(matches!(parse_mpd(xml), Err(Error::Manifest(_))));
+    }
+
+    #[test]
+    fn nested_base_urls() {
+        let xml = mpd(
+            "PT1S",
+            ("https://cdn.example.com/media/", "period/", "../track/"),
+            r#"<SegmentTemplate media="$Number$.mp4">
+                 <SegmentTimeline><S d="1"/></SegmentTimeline>
+               </SegmentTemplate>"#,
+        );
+        let stream = parse_mpd(&xml).unwrap();
+        assert_eq!(stream.urls, ["https://cdn.example.com/media/track/1.mp4"]);
+    }
+
+    #[test]
+    fn padded_number() {
+        let xml = mpd(
+            "PT1S",
+            BASE,
+            r#"<SegmentTemplate media="$RepresentationID$/$Number%05d$.mp4" startNumber="7">
+                 <SegmentTimeline><S d="1" r="1"/></SegmentTimeline>
+               </SegmentTemplate>"#,
+        );
+        let stream = parse_mpd(&xml).unwrap();
+        assert_eq!(
+            stream.urls,
+            [
+                "https://example.com/FLAC,44100,16/00007.mp4",
+                "https://example.com/FLAC,44100,16/00008.mp4",
+            ]
+        );
+    }
+
+    #[test]
+    fn content_protection_is_rejected() {
+        let xml = r#"<MPD mediaPresentationDuration="PT1S"><Period><AdaptationSet contentType="audio">
+              <ContentProtection schemeIdUri="urn:mpeg:dash:mp4protection:2011" value="cenc"/>
+              <Representation><SegmentTemplate media="$Number$.mp4" duration="1"/></Representation>
+            </AdaptationSet></Period></MPD>"#;
+        assert!(matches!(parse_mpd(xml), Err(Error::Unsupported(_))));
+    }
+}
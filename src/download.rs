@@ -1,37 +1,181 @@
 use std::{
+    borrow::Cow,
     cmp::min,
-    fs::{self, File},
-    io::Write,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use crate::{constants::*, config::AudioQuality};
+use crate::{config::{Config, LoudnessAnalysis}, loudness};
 use base64::Engine;
-use futures::{stream, Future, StreamExt};
-use http::{HeaderMap, HeaderValue};
+use futures::StreamExt;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use lofty::{Accessor, PictureType, Probe, Tag, TagExt, TaggedFileExt, TagType};
-use reqwest::{Client, Url};
-use serde_json::Value;
-use tokio::task;
+use lofty::{
+    mp4::{AdvisoryRating, Atom, AtomData, AtomIdent, Ilst},
+    Accessor, ItemKey, ItemValue, PictureType, Probe, Tag, TagExt, TagItem, TagType,
+    TaggedFileExt,
+};
+use reqwest::{header::RANGE, Client, Response, StatusCode};
 
 use crate::{
+    archive::{Entry, TRACK_ID_TAG},
     constants::TIDAL_BASE,
-    tidal_client::{remove_non_alphanumeric, TidalClient},
-    UrlId, UrlType,
+    cover::{
+        resource_url, CoverCache, ARTIST_PICTURE_SIZE, PLAYLIST_IMAGE_SIZE,
+        PLAYLIST_SQUARE_IMAGE_SIZE,
+    },
+    dash::parse_mpd,
+    decrypt::{StreamDecryptor, StreamKey},
+    error::{Error, Result},
+    models::{
+        Album, Artist, Credit, Item, Lyrics, Manifest, MediaItem, PlaybackInfo, Playlist, Track,
+        Video,
+    },
+    retry::{self, RetryPolicy},
+    throttle::Throttle,
+    sanitize::Sanitizer,
+    template::{Template, PLAYLIST_FIELDS, TRACK_FIELDS},
+    tidal_client::TidalClient,
+    video, UrlId, UrlType,
 };
 
+// A stream that sends nothing for this long is given up on and requested again
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+// How much of a partial file is requested again when it is resumed, and compared with what
+// the file holds: a .part left by another stream (e.g. of another audio_quality) must not be
+// continued
+const RESUME_OVERLAP: u64 = 4096;
+
 #[derive(Default, Debug, Clone)]
 pub struct TrackInfo {
     title: String,
     album: String,
+    album_id: u32,
     artist: String,
     artists: String,
-    cover_id: String,
+    album_artist: String,
+    cover_id: Option<String>,
     track_number: u32,
+    disc_number: u32,
+    track_total: Option<u32>,
+    disc_total: Option<u32>,
     track_id: u32,
+    year: Option<String>,
+    release_date: Option<String>,
+    isrc: Option<String>,
+    upc: Option<String>,
+    copyright: Option<String>,
+    version: Option<String>,
+    explicit: bool,
     audio_quality: String,
+    // ReplayGain in dB and peak as a linear sample amplitude. The track values come with the
+    // track listing, the album values only with the playback info.
+    track_gain: Option<f64>,
+    track_peak: Option<f64>,
+    album_gain: Option<f64>,
+    album_peak: Option<f64>,
+    // Plain and time-synced (LRC) lyrics, fetched along with the stream
+    lyrics: Option<String>,
+    synced_lyrics: Option<String>,
+    // (role, name) pairs such as ("Composer", "Johann Sebastian Bach")
+    credits: Vec<(String, String)>,
+}
+
+impl TrackInfo {
+    // `album` is the full /albums/{id} response, which carries the track and disc totals
+    fn new(track: Track, album: Option<&Album>) -> TrackInfo {
+        let artists: Vec<String> = track
+            .artists
+            .iter()
+            .map(|artist| artist.name.clone())
+            .collect();
+        let artist = track.artist_name();
+        let album_artist = album
+            .and_then(|album| album.artist.as_ref())
+            .or(track.album.artist.as_ref())
+            .map(|artist| artist.name.clone())
+            .unwrap_or_else(|| artist.clone());
+        // Not streamStartDate, which is when the track was added to TIDAL
+        let release_date = album.and_then(|album| album.release_date.clone());
+        TrackInfo {
+            title: track.title,
+            album: track.album.title,
+            album_id: track.album.id,
+            artists: if artists.is_empty() {
+                artist.clone()
+            } else {
+                artists.join(", ")
+            },
+            artist,
+            album_artist,
+            cover_id: track.album.cover,
+            track_number: track.track_number,
+            disc_number: track.volume_number,
+            track_total: album.and_then(|album| album.number_of_tracks),
+            disc_total: album.and_then(|album| album.number_of_volumes),
+            track_id: track.id,
+            year: release_date
+                .as_ref()
+                .and_then(|date| date.get(..4).map(|year| year.to_string())),
+            release_date: release_date.and_then(|date| date.get(..10).map(|date| date.to_string())),
+            isrc: track.isrc,
+            upc: album.and_then(|album| album.upc.clone()),
+            copyright: track
+                .copyright
+                .or_else(|| album.and_then(|album| album.copyright.clone())),
+            version: track.version.filter(|version| !version.is_empty()),
+            explicit: track.explicit,
+            audio_quality: track.audio_quality.unwrap_or_default(),
+            track_gain: track.replay_gain,
+            track_peak: track.peak,
+            album_gain: None,
+            album_peak: None,
+            lyrics: None,
+            synced_lyrics: None,
+            credits: Vec::new(),
+        }
+    }
+
+    // Value of a naming template placeholder, see template::TRACK_FIELDS
+    fn template_value(&self, name: &str) -> Option<String> {
+        match name {
+            "artist" => Some(self.artist.clone()),
+            "album_artist" => Some(self.album_artist.clone()),
+            "album" => Some(self.album.clone()),
+            "year" => self.year.clone(),
+            "track_number" => Some(self.track_number.to_string()),
+            "disc_number" => Some(self.disc_number.to_string()),
+            "title" => Some(self.title.clone()),
+            "quality" => Some(self.audio_quality.clone()),
+            "explicit" => Some(if self.explicit { "E" } else { "" }.to_string()),
+            "track_id" => Some(self.track_id.to_string()),
+            _ => None,
+        }
+    }
+
+    // Value of an album_template placeholder. The folder is named before any stream is
+    // resolved, so {quality} is the requested audio_quality: the listing only has the best
+    // quality the album exists in, which the account may not get.
+    fn folder_value(&self, name: &str, config: &Config) -> Option<String> {
+        match name {
+            "quality" => Some(config.audio_quality.api_name().to_string()),
+            _ => self.template_value(name),
+        }
+    }
+}
+
+// Tracks of one url and where they go
+pub struct Listing {
+    pub tracks: Vec<TrackInfo>,
+    // Only collected with download_videos, they go into the same folder as the tracks
+    pub videos: Vec<Video>,
+    pub dl_path: String,
+    // False for playlists, whose folder mixes tracks of many albums
+    pub album_folder: bool,
+    // Images such as artist.jpg or folder.jpg to save along with the tracks, as (path, url)
+    pub images: Vec<(PathBuf, String)>,
 }
 
 pub async fn get_tracks_from_id(
@@ -39,349 +183,1057 @@ pub async fn get_tracks_from_id(
     r_client: Client,
     url_id: UrlId,
     url_type: &UrlType,
-) -> (Vec<TrackInfo>, String) {
-    let mut v: Vec<TrackInfo> = Vec::new();
-    let params = [("countryCode", t_client.user_info.country_code.as_str()),
-    ("limit", "10")];
+) -> Result<Listing> {
+    let country_code = t_client.country_code();
+    let params = [("countryCode", country_code.as_str())];
     let id = match url_id {
         UrlId::Primary(url_id) => url_id.to_string(),
         UrlId::Playlist(url_id) => url_id,
     };
-    let mut header = HeaderMap::new();
-    let token = format!("Bearer {}", t_client.user_info.access_token);
-    header.insert(
-        "authorization",
-        HeaderValue::from_str(token.as_str()).unwrap(),
-    );
-    
+
     let url = match url_type {
         UrlType::Track => format!("{}/tracks/{}", TIDAL_BASE, id),
         UrlType::Album => format!("{}/albums/{}/items", TIDAL_BASE, id),
-        UrlType::Artist => todo!(),
+        UrlType::Artist => {
+            return Err(Error::Unsupported(String::from(
+                "artist urls are expanded into albums first",
+            )))
+        }
+        UrlType::Video => {
+            return Err(Error::Unsupported(String::from(
+                "video urls are downloaded by video::download_video",
+            )))
+        }
         UrlType::Playlist => format!("{}/playlists/{}/items", TIDAL_BASE, id),
     };
-    
-    let urls = reqwest::Url::parse_with_params(&url, params).unwrap();
-    let resp = r_client.get(urls).headers(header.clone()).send().await.unwrap();
-    let result = resp.json::<serde_json::Value>().await.unwrap();
-    let vec_value: Vec<Value> = vec![result.clone()];
-    let tracks = if *url_type != UrlType::Track {
-        result["items"].as_array().unwrap()
+
+    let mut tracks: Vec<Track> = Vec::new();
+    let mut videos: Vec<Video> = Vec::new();
+    if *url_type != UrlType::Track {
+        let items: Vec<Item> = t_client.api_get_all(&r_client, &url, &[]).await?;
+        for item in items {
+            match item.item {
+                MediaItem::Track(track) => tracks.push(*track),
+                MediaItem::Video(video) if t_client.config.download_videos => videos.push(video),
+                MediaItem::Video(_) | MediaItem::Other => {}
+            }
+        }
+    } else {
+        let resp = t_client.api_get(&r_client, &url, &params).await?;
+        tracks.push(resp.json::<Track>().await?);
+    }
+    // Album details (disc and track totals, album artist, release date) aren't part of
+    // the track listing, fetch them once per album
+    let mut albums: HashMap<u32, Option<Album>> = HashMap::new();
+    for track in &tracks {
+        if albums.contains_key(&track.album.id) {
+            continue;
+        }
+        let url = format!("{}/albums/{}", TIDAL_BASE, track.album.id);
+        let album = match t_client.api_get(&r_client, &url, &params).await {
+            Ok(resp) => resp.json::<Album>().await.map_err(Error::from),
+            Err(e) => Err(e),
+        };
+        // The tracks can still be downloaded, only without totals and release date
+        let album = match album {
+            Ok(album) => Some(album),
+            Err(e) => {
+                let _ = t_client
+                    .progress
+                    .println(format!("Cannot fetch album {}: {}", track.album.id, e));
+                None
+            }
+        };
+        albums.insert(track.album.id, album);
+    }
+    let credits = if t_client.config.credits {
+        get_credits(t_client, &r_client, albums.keys()).await
     } else {
-        &vec_value
+        HashMap::new()
     };
-   // println!("{}",tracks.len());
-    for mut track in tracks {
-        if *url_type != UrlType::Track {
-            track = &track["item"];
-        }
-        let cover_id = track["album"]["cover"]
-            .as_str()
-            .unwrap()
-            .to_string()
-            .replace("-", "/");
-        let album = track["album"]["title"].as_str().unwrap().to_string();
-        let title = track["title"].as_str().unwrap().to_string();
-        let track_id = track["id"].to_string().parse::<u32>().unwrap();
-        let track_number = track["trackNumber"].to_string().parse::<u32>().unwrap();
-        let artists = &track["artists"].as_array().unwrap();
-        let artists: Vec<String> = artists
-            .iter()
-            .map(|v| v["name"].as_str().unwrap().to_string())
-            .collect();
-        let artists = artists.join(", ");
-        let artist = track["artist"]["name"].as_str().unwrap().to_string();
-        let audio_quality = track["audioQuality"].as_str().unwrap().to_string();
-
-        v.push(TrackInfo {
-            title,
-            album,
-            artists,
-            artist,
-            cover_id,
-            track_number,
-            track_id,
-            audio_quality,
+    let v: Vec<TrackInfo> = tracks
+        .into_iter()
+        .map(|track| {
+            let album = albums.get(&track.album.id).and_then(|album| album.as_ref());
+            let track_credits = credits.get(&track.id).cloned().unwrap_or_default();
+            let mut info = TrackInfo::new(track, album);
+            info.credits = track_credits
+                .into_iter()
+                .flat_map(|credit| {
+                    let role = credit.credit_type;
+                    credit
+                        .contributors
+                        .into_iter()
+                        .map(move |contributor| (role.clone(), contributor.name))
+                })
+                .collect();
+            info
         })
-    }let mut dl_path: String = t_client.config.download_path.clone() + "/";
+        .collect();
+    let config = &t_client.config;
+    let sanitizer = Sanitizer::new(config);
+    let mut dl_path: String = config.download_path.clone() + "/";
+    let mut images = Vec::new();
     if *url_type == UrlType::Playlist {
-        let url = reqwest::Url::parse_with_params(format!("{}/playlists/{}", TIDAL_BASE, id).as_str(), params).unwrap();
-        let resp = r_client.get(url).headers(header).send().await.unwrap();
-        let title = resp.json::<serde_json::Value>().await.unwrap().get("title").unwrap().as_str().unwrap().to_string();
-        dl_path.push_str(format!("Playlist/{}",title).as_str());
-    } else {
-        dl_path.push_str(format!("Album/{}/{}", v[0].artist, v[0].album).as_str())
+        let url = format!("{}/playlists/{}", TIDAL_BASE, id);
+        let resp = t_client.api_get(&r_client, &url, &params).await?;
+        let playlist = resp.json::<Playlist>().await?;
+        let template =
+            Template::parse(&config.playlist_template, "playlist_template", PLAYLIST_FIELDS)?;
+        let rendered = template.render(&|name| match name {
+            "playlist" => Some(sanitizer.value(&playlist.title)),
+            _ => None,
+        });
+        dl_path.push_str(&sanitizer.path(&rendered, None));
+        let image = match (&playlist.square_image, &playlist.image) {
+            (Some(square_image), _) => Some(resource_url(square_image, PLAYLIST_SQUARE_IMAGE_SIZE)),
+            (None, Some(image)) => Some(resource_url(image, PLAYLIST_IMAGE_SIZE)),
+            (None, None) => None,
+        };
+        if let Some(image) = image {
+            images.push((Path::new(&dl_path).join("folder.jpg"), image));
+        }
+    } else if let Some(first) = v.first() {
+        let template = Template::parse(&config.album_template, "album_template", TRACK_FIELDS)?;
+        let rendered =
+            template.render(&|name| first.folder_value(name, config).map(|v| sanitizer.value(&v)));
+        dl_path.push_str(&sanitizer.path(&rendered, None));
+        if let Some(artist_dir) = artist_dir(config, &sanitizer, first)? {
+            let album = albums.get(&first.album_id).and_then(|album| album.as_ref());
+            let artist = album.and_then(|album| album.artist.clone());
+            if let Some(picture) = get_artist_picture(t_client, &r_client, artist).await {
+                let artist_dir = config.download_path.clone() + "/" + &artist_dir;
+                images.push((
+                    Path::new(&artist_dir).join("artist.jpg"),
+                    resource_url(&picture, ARTIST_PICTURE_SIZE),
+                ));
+            }
+        }
+    }
+    Ok(Listing {
+        tracks: v,
+        videos,
+        dl_path,
+        album_folder: *url_type != UrlType::Playlist,
+        images,
+    })
+}
+
+// Folder of the first album_template component that names the artist, e.g. "Album/{artist}"
+// for the default template. None if no folder is specific to the artist.
+fn artist_dir(config: &Config, sanitizer: &Sanitizer, track: &TrackInfo) -> Result<Option<String>> {
+    let components: Vec<&str> = config.album_template.split('/').collect();
+    for (i, component) in components.iter().enumerate() {
+        let template = Template::parse(component, "album_template", TRACK_FIELDS)?;
+        if !template.has_field("artist") && !template.has_field("album_artist") {
+            continue;
+        }
+        // The album folder itself, which also holds the album's tracks
+        if i + 1 == components.len() {
+            return Ok(None);
+        }
+        let template =
+            Template::parse(&components[..=i].join("/"), "album_template", TRACK_FIELDS)?;
+        let rendered =
+            template.render(&|name| track.folder_value(name, config).map(|v| sanitizer.value(&v)));
+        return Ok(Some(sanitizer.path(&rendered, None)));
+    }
+    Ok(None)
+}
+
+// Artist folder (see artist_dir) for files that belong to the artist rather than an album,
+// or download_path if album_template has none
+pub fn artist_folder(config: &Config, artist_name: &str) -> Result<String> {
+    let sanitizer = Sanitizer::new(config);
+    let track = TrackInfo {
+        artist: artist_name.to_string(),
+        album_artist: artist_name.to_string(),
+        ..Default::default()
+    };
+    Ok(match artist_dir(config, &sanitizer, &track)? {
+        Some(artist_dir) => config.download_path.clone() + "/" + &artist_dir,
+        None => config.download_path.clone(),
+    })
+}
+
+pub async fn get_artist(t_client: &TidalClient, r_client: &Client, artist_id: u32) -> Result<Artist> {
+    let url = format!("{}/artists/{}", TIDAL_BASE, artist_id);
+    let country_code = t_client.country_code();
+    let params = [("countryCode", country_code.as_str())];
+    let resp = t_client.api_get(r_client, &url, &params).await?;
+    Ok(resp.json::<Artist>().await?)
+}
+
+// The artist inside an album usually carries its picture, otherwise ask /artists/{id}
+async fn get_artist_picture(
+    t_client: &TidalClient,
+    r_client: &Client,
+    artist: Option<Artist>,
+) -> Option<String> {
+    let artist = artist?;
+    if artist.picture.is_some() {
+        return artist.picture;
     }
-    //println!("{}", v.len());
-    (v, dl_path)
+    get_artist(t_client, r_client, artist.id).await.ok()?.picture
 }
 
-pub async fn download(t_client: &TidalClient, tracks: &Vec<TrackInfo>, url_type: &UrlType, dl_path: String) {
-    let a = tracks.len();
-    let audio_quality = match t_client.config.audio_quality {
-        AudioQuality::LOW =>  "LOW",
-        AudioQuality::HIGH =>  "HIGH",
-        AudioQuality::LOSSLESS =>  "LOSSLESS",
-        AudioQuality::MASTER => "HI_RES"
+// Credits of every track on the given albums, keyed by track id. Fetched per album
+// rather than per track; an album whose credits can't be fetched is skipped with a warning.
+async fn get_credits(
+    t_client: &TidalClient,
+    r_client: &Client,
+    album_ids: impl Iterator<Item = &u32>,
+) -> HashMap<u32, Vec<Credit>> {
+    let mut credits = HashMap::new();
+    for album_id in album_ids {
+        let url = format!("{}/albums/{}/items/credits", TIDAL_BASE, album_id);
+        let params = [("replace", "true"), ("includeContributors", "true")];
+        match t_client.api_get_all::<Item>(r_client, &url, &params).await {
+            Ok(items) => {
+                for item in items {
+                    if let MediaItem::Track(track) = item.item {
+                        credits.insert(track.id, item.credits);
+                    }
+                }
+            }
+            Err(e) => {
+                let _ = t_client
+                    .progress
+                    .println(format!("Cannot fetch credits of album {}: {}", album_id, e));
+            }
+        }
+    }
+    credits
+}
+
+// Credit roles that are about the release rather than its performance, or that the artist
+// tags hold already
+const NON_PERFORMER_ROLES: &[&str] = &[
+    "Main Artist",
+    "Featured Artist",
+    "Record Label",
+    "Label",
+    "Music Publisher",
+    "Publisher",
+    "Copyright",
+    "A&R",
+    "Art Direction",
+    "Artwork",
+    "Design",
+    "Graphic Design",
+    "Photography",
+    "Liner Notes",
+    "Management",
+    "Studio Personnel",
+    "Editor",
+    "Contractor",
+];
+
+// Tag for a TIDAL credit role. Writing, production and engineering roles have their own tags,
+// every other role is taken to be an instrument or voice: its people become performers with
+// the role in parentheses, the usual Vorbis comment convention.
+fn credit_tag(role: &str, name: &str) -> Option<(ItemKey, String)> {
+    let key = match role {
+        "Composer" => ItemKey::Composer,
+        "Lyricist" => ItemKey::Lyricist,
+        "Writer" | "Songwriter" => ItemKey::Writer,
+        "Arranger" | "Orchestrator" => ItemKey::Arranger,
+        "Conductor" => ItemKey::Conductor,
+        "Remixer" => ItemKey::Remixer,
+        "Performer" => return Some((ItemKey::Performer, name.to_string())),
+        role if NON_PERFORMER_ROLES.contains(&role) => return None,
+        // e.g. Mixer, Mixing Engineer, Assistant Mixing Engineer
+        role if role.contains("Mix") => ItemKey::MixEngineer,
+        // e.g. Recording Engineer, Mastering Engineer, Vocal Engineer
+        role if role.contains("Engineer") => ItemKey::Engineer,
+        // e.g. Co-Producer, Executive Producer, Additional Production
+        role if role.contains("Produc") => ItemKey::Producer,
+        _ => ItemKey::Performer,
+    };
+    let value = if key == ItemKey::Performer {
+        format!("{} ({})", name, role.to_lowercase())
+    } else {
+        name.to_string()
     };
+    Some((key, value))
+}
+
+// Album ids of an artist's discography, filtered by the artist_* config options
+pub async fn get_albums_from_artist(
+    t_client: &TidalClient,
+    r_client: Client,
+    artist_id: u32,
+) -> Result<Vec<u32>> {
+    let url = format!("{}/artists/{}/albums", TIDAL_BASE, artist_id);
+    let mut filters: Vec<&[(&str, &str)]> = Vec::new();
+    if t_client.config.artist_albums {
+        filters.push(&[]);
+    }
+    if t_client.config.artist_eps_singles {
+        filters.push(&[("filter", "EPSANDSINGLES")]);
+    }
+    if t_client.config.artist_compilations {
+        filters.push(&[("filter", "COMPILATIONS")]);
+    }
+    let mut album_ids: Vec<u32> = Vec::new();
+    for filter in filters {
+        let albums: Vec<Album> = t_client.api_get_all(&r_client, &url, filter).await?;
+        for album in albums {
+            if !album_ids.contains(&album.id) {
+                album_ids.push(album.id);
+            }
+        }
+    }
+    Ok(album_ids)
+}
+
+// Downloads every track, carrying on past failed ones. Returns `Error::Incomplete`
+// listing the failed tracks if any of them could not be downloaded.
+//
+// Tracks are resolved (playback info, lyrics) a few at a time and each transfer starts as
+// soon as its track is resolved and one of the download slots shared by every url is free.
+pub async fn download(t_client: &TidalClient, r_client: &Client, listing: &Listing) -> Result<()> {
+    let tracks = &listing.tracks;
+    let dl_path = &listing.dl_path;
+    let audio_quality = t_client.config.audio_quality.api_name();
     let params = [
         ("audioquality", audio_quality),
         ("playbackmode", "STREAM"),
         ("assetpresentation", "FULL"),
-        ("limit", "50"),
     ];
-    let mut header = HeaderMap::new();
-    let token = format!("Bearer {}", t_client.user_info.access_token);
-    header.insert(
-        "authorization",
-        HeaderValue::from_str(token.as_str()).unwrap(),
-    );
-    let mut urls = vec![];
-    for i in 0..tracks.len() {
-        let (url, file_name) = download_track(header.clone(), &params, tracks, i, &url_type, &audio_quality).await;
-        urls.push((url, file_name));
-    }
-    let client = Client::new();
-    let m = MultiProgress::new();
-    let bodies = futures::stream::iter(urls).enumerate()
-        .map(|(i,(url, file_name))| -> task::JoinHandle<()> {
-            let c = tracks.to_vec();
-            let client = client.clone();
-            let pb = m.add(ProgressBar::new(0));
-            let mut dl_path = dl_path.clone();
-            let save_cover = t_client.config.save_cover;
-            let exist_check = t_client.config.exist_check;
-            tokio::spawn(async move {
-                let resp = client.get(url.clone()).send().await.unwrap();
-                pb.set_length(
-                    resp.content_length()
-                        .ok_or(format!("Failed to get content length from ''"))
-                        .unwrap(),
-                );
-                pb.set_style(
-                    ProgressStyle::default_bar()
-                        .template("{msg:.cyan} [{bytes}/{total_bytes}] [{elapsed_precise}] [{wide_bar:.green}] ({bytes_per_sec}, {eta})")
-                        .unwrap()
-                        .progress_chars("#>="),
-                );
-                
-                let total_size = resp.content_length().unwrap();
-                let mut stream = resp.bytes_stream();
-                fs::create_dir_all(dl_path.clone()).unwrap();
-                dl_path = format!("{}/{}",dl_path,file_name);
-                pb.set_message(file_name);
-                if exist_check {
-                let file_exist = Path::new(&dl_path).exists();
-                
-                if file_exist  {
-                    pb.set_position(100);
-                    pb.finish_with_message("File already exists! Skip downloading..");
-                    return ;
+    let config = &t_client.config;
+    let retry = RetryPolicy::new(config);
+    if config.save_cover {
+        save_images(&t_client.progress, r_client, &retry, &listing.images).await;
+    }
+    let covers = CoverCache::new(retry);
+    // resolve_slots bounds how far tracks are resolved ahead for the whole run, this only
+    // keeps a large listing from queueing all of its tracks at once
+    let lookahead = config.max_downloads + config.max_api_requests;
+    let results: Vec<_> = futures::stream::iter(0..tracks.len())
+        .map(|i| {
+            let covers = covers.clone();
+            let params = &params;
+            async move {
+                let archive = t_client.archive.as_ref();
+                let track_id = tracks[i].track_id;
+                if archive.is_some_and(|archive| !archive.try_claim(track_id, audio_quality)) {
+                    let _ = t_client.progress.println(format!(
+                        "{}: in the download archive or downloaded for another url, skipped",
+                        tracks[i].title
+                    ));
+                    return (i, Ok(None));
                 }
-            }
-                let mut file = std::fs::File::create(dl_path.clone())
-                    .or(Err(format!("Failed to create file '")))
-                    .unwrap();
-                let mut downloaded: u64 = 0;
-                while let Some(item) = stream.next().await {
-                    let chunk = item
-                        .or(Err(format!("Error while downloading file")))
-                        .unwrap();
-                    file.write_all(&chunk)
-                        .or(Err(format!("Error while writing to file")))
-                        .unwrap();
-                    let new = min(downloaded + (chunk.len() as u64), total_size);
-                    downloaded = new;
-                    pb.set_position(new);
+                let resolving = t_client
+                    .resolve_slots
+                    .acquire()
+                    .await
+                    .expect("resolve_slots is never closed");
+                let stream = match download_track(t_client, r_client, params, tracks, i).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let _ = t_client.progress.println(format!("{}: {}", tracks[i].title, e));
+                        if let Some(archive) = archive {
+                            archive.release(track_id, audio_quality);
+                        }
+                        return (i, Err(e));
+                    }
+                };
+                let dl_path = format!("{}/{}", dl_path, stream.file_name);
+                if !t_client.claim_path(Path::new(&dl_path)) {
+                    let _ = t_client.progress.println(format!(
+                        "{}: being downloaded for another url, skipped",
+                        tracks[i].title
+                    ));
+                    if let Some(archive) = archive {
+                        archive.release(track_id, audio_quality);
+                    }
+                    return (i, Ok(None));
                 }
-                pb.set_message("Writing ID3");
-                write_metadata(&c[i], client, dl_path, save_cover).await;
-                pb.finish_with_message(format!("Downloaded"));
-            })
-        })
-        .buffer_unordered(tracks.len());
-    bodies
-        .for_each(|b| async {
-            match b {
-                Ok(o) => {}
-                Err(e) => eprintln!("Got a tokio::JoinError: {}", e),
+                let slot = t_client
+                    .download_slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("download_slots is never closed");
+                drop(resolving);
+                let client = r_client.clone();
+                let pb = t_client.progress.add(ProgressBar::new(0));
+                // Also with disc_folders the folder cover goes into the album folder
+                let cover_dir = listing.album_folder.then(|| PathBuf::from(&listing.dl_path));
+                let claimed_path = dl_path.clone();
+                let throttle = t_client.throttle.clone();
+                let config = config.clone();
+                pb.set_message(stream.file_name.clone());
+                let handle = tokio::spawn(async move {
+                    let result = download_file(
+                        &client,
+                        &covers,
+                        &throttle,
+                        &stream,
+                        &dl_path,
+                        cover_dir.as_deref(),
+                        &pb,
+                        &config,
+                    )
+                    .await;
+                    drop(slot);
+                    if let Err(e) = &result {
+                        pb.abandon_with_message(format!("Failed: {}", e));
+                    }
+                    result.map(|()| (stream.track, PathBuf::from(dl_path)))
+                });
+                let result = handle
+                    .await
+                    .unwrap_or_else(|e| Err(Error::Io(std::io::Error::other(e))));
+                t_client.release_path(Path::new(&claimed_path));
+                match (archive, &result) {
+                    (Some(archive), Ok((track, path))) => {
+                        // The track is fine, only the next run may download it again
+                        if let Err(e) = Entry::new(track.track_id, audio_quality, path)
+                            .and_then(|entry| archive.record(entry))
+                        {
+                            archive.release(track_id, audio_quality);
+                            let _ = t_client.progress.println(format!(
+                                "Cannot add {} to the download archive: {}",
+                                path.display(),
+                                e
+                            ));
+                        }
+                    }
+                    (Some(archive), Err(_)) => archive.release(track_id, audio_quality),
+                    (None, _) => {}
+                }
+                (i, result.map(Some))
             }
         })
+        .buffer_unordered(lookahead)
+        .collect()
         .await;
+    let mut failed: Vec<(String, Error)> = Vec::new();
+    let mut downloaded = Vec::new();
+    for (i, result) in results {
+        match result {
+            Ok(Some(file)) => downloaded.push(file),
+            Ok(None) => {}
+            Err(e) => failed.push((tracks[i].title.clone(), e)),
+        }
+    }
+    if config.loudness_analysis != LoudnessAnalysis::Off {
+        analyze_loudness(&t_client.progress, config, downloaded).await;
+    }
+    failed.extend(video::download_videos(t_client, r_client, &listing.videos, dl_path).await);
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Incomplete {
+            total: tracks.len() + listing.videos.len(),
+            failed,
+        })
+    }
+}
 
-    /*
-    let mut downloads = vec![];
-
-    let mut path = String::new();
-    for i in 0..tracks.len() {
-        let (a, f_n, path_tmp) = download_track(header.clone(), &params, tracks, i).await;
-        path = path_tmp;
-        let url = Url::from_str(a.as_str()).unwrap();
-        downloads.push(trauma::download::Download::new(&url, &f_n))
-    }
-    let downloader = DownloaderBuilder::new()
-        .directory(PathBuf::from(path))
-        .build();
-    downloader.download(&downloads).await;
-    */
+// Measure the loudness of the downloaded tracks, one album at a time. The album gain is
+// only written when the whole album was downloaded, which playlists rarely do. Failures
+// are reported but don't fail the download, the files themselves are fine.
+async fn analyze_loudness(
+    progress: &MultiProgress,
+    config: &Config,
+    downloaded: Vec<(TrackInfo, PathBuf)>,
+) {
+    let mut albums: BTreeMap<u32, Vec<(TrackInfo, PathBuf)>> = BTreeMap::new();
+    for (track, path) in downloaded {
+        albums.entry(track.album_id).or_default().push((track, path));
+    }
+    for (_, mut album) in albums {
+        // TIDAL's values are only in the tags when replay_gain is on
+        let tidal_complete = config.replay_gain
+            && album
+                .iter()
+                .all(|(track, _)| track.track_gain.is_some() && track.album_gain.is_some());
+        if config.loudness_analysis == LoudnessAnalysis::Missing && tidal_complete {
+            continue;
+        }
+        album.sort_by_key(|(track, _)| (track.disc_number, track.track_number));
+        let album_gain = album
+            .first()
+            .and_then(|(track, _)| track.track_total)
+            .is_some_and(|total| total as usize == album.len());
+        let _ = progress.println(format!(
+            "Analyzing loudness of {} ({} tracks)",
+            album[0].0.album,
+            album.len()
+        ));
+        let paths: Vec<PathBuf> = album.into_iter().map(|(_, path)| path).collect();
+        let failures =
+            tokio::task::spawn_blocking(move || loudness::analyze_album(&paths, album_gain)).await;
+        match failures {
+            Ok(failures) => {
+                for (path, e) in failures {
+                    let _ = progress.println(format!(
+                        "Loudness analysis failed for {}: {}",
+                        path.display(),
+                        e
+                    ));
+                }
+            }
+            Err(e) => {
+                let _ = progress.println(format!("Loudness analysis failed: {}", e));
+            }
+        }
+    }
 }
 
-pub async fn write_metadata(track: &TrackInfo, request: Client, path_str: String, save_cover: bool) {
-    
-    let cover_url = format!(
-        "https://resources.tidal.com/images/{}/1280x1280.jpg",
-        track.cover_id
-    );
-    //eprintln!("{:?}", track);
-    let cover = request
-        .get(cover_url)
-        .send()
-        .await
+// Save the images of a listing that aren't there yet. They are nice to have, so failures are
+// only reported.
+async fn save_images(
+    progress: &MultiProgress,
+    client: &Client,
+    retry: &RetryPolicy,
+    images: &[(PathBuf, String)],
+) {
+    for (path, url) in images {
+        if path.exists() {
+            continue;
+        }
+        let result = async {
+            let resp = retry.send(client.get(url)).await?;
+            if !resp.status().is_success() {
+                return Err(Error::Http {
+                    status: resp.status(),
+                    url: url.to_string(),
+                });
+            }
+            let image = resp.bytes().await?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, image)?;
+            Ok(())
+        };
+        if let Err(e) = result.await {
+            let _ = progress.println(format!("Cannot save {}: {}", path.display(), e));
+        }
+    }
+}
+
+// Fetch `urls` in order and concatenate them into `dl_path`. Plain streams have a
+// single url, DASH streams an initialization segment followed by media segments.
+#[allow(clippy::too_many_arguments)]
+async fn download_file(
+    client: &Client,
+    covers: &CoverCache,
+    throttle: &Throttle,
+    stream: &TrackStream,
+    dl_path: &str,
+    cover_dir: Option<&Path>,
+    pb: &ProgressBar,
+    config: &Config,
+) -> Result<()> {
+    if config.exist_check && Path::new(dl_path).exists() {
+        pb.set_position(100);
+        pb.finish_with_message("File already exists! Skip downloading..");
+        return Ok(());
+    }
+    pb.set_style(transfer_style());
+    if let Some(parent) = Path::new(dl_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // The track only appears under its real name once it is complete and tagged, so
+    // exist_check never mistakes an interrupted download for a finished one. A failed
+    // download keeps its .part file for the next run to resume.
+    let part_path = part_path(dl_path);
+    write_stream(
+        client,
+        &RetryPolicy::new(config),
+        throttle,
+        &stream.urls,
+        stream.key.as_ref(),
+        &part_path,
+        pb,
+    )
+    .await?;
+    pb.set_message("Writing ID3");
+    let track = &stream.track;
+    // Some releases have no artwork at all (`album.cover` is null)
+    if let (true, Some(cover_id), Some(cover_dir)) = (config.save_cover, &track.cover_id, cover_dir) {
+        let cover_path = cover_dir.join("cover.jpg");
+        if !cover_path.exists() {
+            let cover = covers.get(client, cover_id, &config.folder_cover_size).await?;
+            fs::write(cover_path, cover)?;
+        }
+    }
+    let cover = match (config.embed_cover, &track.cover_id) {
+        (true, Some(cover_id)) => {
+            Some(covers.get(client, cover_id, &config.embed_cover_size).await?)
+        }
+        _ => None,
+    };
+    if let Err(e) = write_metadata(track, &part_path, cover, config) {
+        // Tagging may have left the file half written
+        let _ = fs::remove_file(&part_path);
+        return Err(e);
+    }
+    fs::rename(&part_path, dl_path)?;
+    if let (true, Some(synced_lyrics)) = (config.save_lrc, &stream.track.synced_lyrics) {
+        fs::write(Path::new(dl_path).with_extension("lrc"), synced_lyrics)?;
+    }
+    pb.finish_with_message("Downloaded");
+    Ok(())
+}
+
+// Progress bar of a running transfer, for tracks and videos alike
+pub fn transfer_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{msg:.cyan} [{bytes}/{total_bytes}] [{elapsed_precise}] [{wide_bar:.green}] ({bytes_per_sec}, {eta})")
         .unwrap()
-        .bytes()
-        .await
-        .unwrap();
-
-        let path = Path::new(path_str.as_str());
-        let mut parent_path = PathBuf::from(path.parent().unwrap());
-    if save_cover {
-        parent_path.push("cover.jpg");
-        if !parent_path.exists() {
-            let mut f = File::create(parent_path).expect("Failed to create cover.jpg");
-            f.write_all(&cover).expect("failed to write cover.jpg");
-        }
-        
-    }
-    if path_str.ends_with(".mp4") {
-        return ;
-    }
-    //head > title
-        //println!("tag: {}", file_name);
-    //eprintln!("{}", path_str);
-    let mut tagged_file = Probe::open(&path).unwrap().read().unwrap();
+        .progress_chars("#>=")
+}
+
+// Where a download is written until it is complete, e.g. "track.flac.part"
+pub fn part_path(dl_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.part", dl_path))
+}
+
+// Fetch the stream into `part_path`. A single-url stream continues where an earlier run
+// stopped if the server honours Range requests and the end of the file matches the stream;
+// segmented streams start over, since the segment boundaries of a partial file are unknown.
+// Within a run, a url whose body breaks off is requested again from the byte it stopped at.
+pub async fn write_stream(
+    client: &Client,
+    retry: &RetryPolicy,
+    throttle: &Throttle,
+    urls: &[String],
+    key: Option<&StreamKey>,
+    part_path: &Path,
+    pb: &ProgressBar,
+) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(part_path)?;
+    if urls.len() > 1 {
+        file.set_len(0)?;
+    }
+    let mut decryptor = key.map(|key| key.cipher());
+    for url in urls {
+        let url_start = if urls.len() == 1 { 0 } else { file.metadata()?.len() };
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            // The file itself tells how much of the url arrived, also after a failed write
+            let mut downloaded = file.metadata()?.len();
+            let overlap = min(downloaded - url_start, RESUME_OVERLAP);
+            let requested = downloaded - overlap;
+            let (resp, start) = request_from(client, retry, url, requested - url_start).await?;
+            let overlap = if url_start + start == requested {
+                overlap
+            } else {
+                // The server sent the whole url again
+                file.set_len(url_start + start)?;
+                downloaded = url_start + start;
+                0
+            };
+            if let Some(decryptor) = &mut decryptor {
+                decryptor.seek(downloaded - overlap);
+            }
+            // Segment sizes are only known once each response arrives
+            let expected = resp.content_length();
+            pb.set_length(downloaded - overlap + expected.unwrap_or(0));
+            pb.set_position(downloaded);
+            let received = match write_body(
+                resp,
+                &mut file,
+                decryptor.as_mut(),
+                overlap,
+                &mut downloaded,
+                throttle,
+                pb,
+            )
+            .await
+            {
+                Ok(Some(received)) => Ok(received),
+                Ok(None) => {
+                    // The partial file belongs to another stream, start the url over
+                    file.set_len(url_start)?;
+                    continue;
+                }
+                Err(e) => Err(e),
+            };
+            let result = match (received, expected) {
+                (Ok(received), Some(expected)) if received != expected => {
+                    Err(Error::Io(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("got {} of {} bytes from {}", received, expected, url),
+                    )))
+                }
+                (received, _) => received.map(|_| ()),
+            };
+            match result {
+                Ok(()) => break,
+                Err(e) if attempt < retry.attempts && retry::is_transient(&e) => {
+                    tokio::time::sleep(retry.delay(attempt)).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+// Append the body of `resp` to `file`, returning how many bytes were received. The first
+// `overlap` bytes are the end of the file again and are compared with it instead, None if they
+// differ. A body that stops arriving for STALL_TIMEOUT fails as timed out, so the download can
+// be retried.
+async fn write_body(
+    resp: Response,
+    file: &mut File,
+    mut decryptor: Option<&mut StreamDecryptor>,
+    mut overlap: u64,
+    downloaded: &mut u64,
+    throttle: &Throttle,
+    pb: &ProgressBar,
+) -> Result<Option<u64>> {
+    let mut received: u64 = 0;
+    let mut body = resp.bytes_stream();
+    loop {
+        let item = tokio::time::timeout(STALL_TIMEOUT, body.next())
+            .await
+            .map_err(|_| {
+                Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("no data received for {}s", STALL_TIMEOUT.as_secs()),
+                ))
+            })?;
+        let chunk = match item {
+            Some(item) => item?,
+            None => return Ok(Some(received)),
+        };
+        throttle.consume(chunk.len()).await;
+        let mut chunk = chunk.to_vec();
+        if let Some(decryptor) = &mut decryptor {
+            decryptor.decrypt(&mut chunk);
+        }
+        received += chunk.len() as u64;
+        let known = min(overlap, chunk.len() as u64) as usize;
+        if known > 0 {
+            // Reads follow the cursor, appends always go to the end of the file
+            let mut existing = vec![0; known];
+            file.seek(SeekFrom::Start(*downloaded - overlap))?;
+            file.read_exact(&mut existing)?;
+            if existing != chunk[..known] {
+                return Ok(None);
+            }
+            overlap -= known as u64;
+        }
+        file.write_all(&chunk[known..])?;
+        *downloaded += (chunk.len() - known) as u64;
+        pb.set_position(min(*downloaded, pb.length().unwrap_or(*downloaded)));
+    }
+}
+
+// GET `url` from byte `offset` on. Returns the response and the offset it actually starts
+// at, which is 0 when the server ignores or rejects the Range request.
+async fn request_from(
+    client: &Client,
+    retry: &RetryPolicy,
+    url: &str,
+    offset: u64,
+) -> Result<(Response, u64)> {
+    if offset > 0 {
+        let resp = retry
+            .send(client.get(url).header(RANGE, format!("bytes={}-", offset)))
+            .await?;
+        match resp.status() {
+            StatusCode::PARTIAL_CONTENT => return Ok((resp, offset)),
+            status if status.is_success() => return Ok((resp, 0)),
+            // e.g. 416 for a partial file longer than the url, try again without Range
+            _ => {}
+        }
+    }
+    let resp = retry.send(client.get(url)).await?;
+    if !resp.status().is_success() {
+        return Err(Error::Http {
+            status: resp.status(),
+            url: url.to_string(),
+        });
+    }
+    Ok((resp, 0))
+}
+
+pub fn write_metadata(
+    track: &TrackInfo,
+    path: &Path,
+    cover: Option<Vec<u8>>,
+    config: &Config,
+) -> Result<()> {
+    // Detect the format from the content, the path may end in .part
+    let mut tagged_file = Probe::open(path)?.guess_file_type()?.read()?;
     let tag = match tagged_file.primary_tag_mut() {
-        Some(primary_tag) => {
-            //eprintln!("{}:{:?}", path_str, primary_tag.tag_type());
-            primary_tag
-        },
+        Some(primary_tag) => primary_tag,
         None => {
             if let Some(first_tag) = tagged_file.first_tag_mut() {
-                //println!("{}:{:?}", path_str, first_tag.tag_type());
                 first_tag
             } else {
                 let tag_type = tagged_file.primary_tag_type();
-                //eprintln!("WARN: No tags found, creating a new tag of type `{tag_type:?}`");
                 tagged_file.insert_tag(Tag::new(tag_type));
 
                 tagged_file.primary_tag_mut().unwrap()
             }
         }
     };
-    
+
     let title = &track.title;
     let artists = &track.artists;
-    let picture = lofty::Picture::new_unchecked(
-        PictureType::CoverFront,
-        lofty::MimeType::Jpeg,
-        None,
-        cover.to_vec(),
-    );
     let tracknumber = track.track_number;
     let album = &track.album;
-    tag.set_picture(0, picture);
+    if let Some(cover) = cover {
+        let picture = lofty::Picture::new_unchecked(
+            PictureType::CoverFront,
+            lofty::MimeType::Jpeg,
+            None,
+            cover,
+        );
+        tag.set_picture(0, picture);
+    }
     tag.set_title(title.to_string());
     tag.set_artist(artists.to_string());
     tag.set_track(tracknumber);
+    if let Some(track_total) = track.track_total {
+        tag.set_track_total(track_total);
+    }
+    tag.set_disk(track.disc_number);
+    if let Some(disc_total) = track.disc_total {
+        tag.set_disk_total(disc_total);
+    }
     tag.set_album(album.to_string());
+    tag.insert_text(ItemKey::AlbumArtist, track.album_artist.clone());
+    let optional = [
+        (ItemKey::RecordingDate, &track.release_date),
+        (ItemKey::Year, &track.year),
+        (ItemKey::Isrc, &track.isrc),
+        (ItemKey::Barcode, &track.upc),
+        (ItemKey::CopyrightMessage, &track.copyright),
+        (ItemKey::TrackSubtitle, &track.version),
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value {
+            tag.insert_text(key, value.clone());
+        }
+    }
+    let mut credits: Vec<(ItemKey, String)> = Vec::new();
+    for credit in track.credits.iter().filter_map(|(role, name)| credit_tag(role, name)) {
+        // TIDAL lists people again for every variant of a role, e.g. Engineer and
+        // Recording Engineer
+        if !credits.contains(&credit) {
+            credits.push(credit);
+        }
+    }
+    for (key, _) in &credits {
+        tag.remove_key(key);
+    }
+    for (key, value) in &credits {
+        tag.push(TagItem::new(key.clone(), ItemValue::Text(value.clone())));
+    }
+    if let (true, Some(lyrics)) = (config.embed_lyrics, &track.lyrics) {
+        tag.insert_text(ItemKey::Lyrics, lyrics.clone());
+    }
+    if config.replay_gain {
+        let gains = [
+            (ItemKey::ReplayGainTrackGain, track.track_gain),
+            (ItemKey::ReplayGainAlbumGain, track.album_gain),
+        ];
+        for (key, gain) in gains {
+            if let Some(gain) = gain {
+                tag.insert_text(key, format!("{:.2} dB", gain));
+            }
+        }
+        let peaks = [
+            (ItemKey::ReplayGainTrackPeak, track.track_peak),
+            (ItemKey::ReplayGainAlbumPeak, track.album_peak),
+        ];
+        for (key, peak) in peaks {
+            if let Some(peak) = peak {
+                tag.insert_text(key, format!("{:.6}", peak));
+            }
+        }
+    }
+    // Vorbis comments have no standard explicit field, ITUNESADVISORY is what most
+    // taggers read (1 = explicit, 0 = clean)
+    if tag.tag_type() == TagType::VorbisComments {
+        let advisory = if track.explicit { "1" } else { "0" };
+        // insert_text drops keys without a known mapping
+        tag.insert_unchecked(TagItem::new(
+            ItemKey::Unknown(String::from("ITUNESADVISORY")),
+            ItemValue::Text(advisory.to_string()),
+        ));
+        tag.insert_unchecked(TagItem::new(
+            ItemKey::Unknown(TRACK_ID_TAG.to_string()),
+            ItemValue::Text(track.track_id.to_string()),
+        ));
+    }
 
-    tag.save_to_path(&path).unwrap();
+    if tag.tag_type() == TagType::Mp4Ilst {
+        // rtng is a binary atom, the generic ParentalAdvisory item would be written as text
+        let mut ilst = Ilst::from(tag.clone());
+        ilst.set_advisory_rating(if track.explicit {
+            AdvisoryRating::Explicit
+        } else {
+            AdvisoryRating::Clean
+        });
+        ilst.replace_atom(Atom::new(
+            AtomIdent::Freeform {
+                mean: Cow::Borrowed("com.apple.iTunes"),
+                name: Cow::Borrowed(TRACK_ID_TAG),
+            },
+            AtomData::UTF8(track.track_id.to_string()),
+        ));
+        // lofty has no MP4 mapping for these roles and Ilst::from drops them, write the
+        // freeform atoms other taggers use
+        let freeform_credits = [
+            (ItemKey::Performer, "PERFORMER"),
+            (ItemKey::Writer, "WRITER"),
+            (ItemKey::Arranger, "ARRANGER"),
+        ];
+        for (key, name) in freeform_credits {
+            let ident = AtomIdent::Freeform {
+                mean: Cow::Borrowed("com.apple.iTunes"),
+                name: Cow::Borrowed(name),
+            };
+            let values = credits
+                .iter()
+                .filter(|(credit_key, _)| *credit_key == key)
+                .map(|(_, value)| AtomData::UTF8(value.clone()))
+                .collect();
+            match Atom::from_collection(ident.clone(), values) {
+                Some(atom) => ilst.replace_atom(atom),
+                None => ilst.remove(&ident),
+            }
+        }
+        ilst.save_to_path(path)?;
+    } else {
+        tag.save_to_path(path)?;
+    }
+    Ok(())
+}
+
+// Resolved media of one track: `urls` are fetched in order, decrypted with `key` if the
+// stream is encrypted, and concatenated into `file_name`. `track` is completed with what
+// the playback info knows better (actual quality, album ReplayGain).
+pub struct TrackStream {
+    pub urls: Vec<String>,
+    pub file_name: String,
+    pub key: Option<StreamKey>,
+    pub track: TrackInfo,
 }
 
 pub async fn download_track<'a>(
-    header: HeaderMap,
+    t_client: &TidalClient,
+    client: &Client,
     param: &'a [(&'a str, &'a str)],
-    tracks: &'a Vec<TrackInfo>,
+    tracks: &'a [TrackInfo],
     index: usize,
-    url_type: &UrlType,
-    audio_quality: &'a str
-) -> (String, String) {
-    let header_arc = Arc::new(Mutex::new(header));
-    let len = tracks.len();
-    let header_copy = Arc::clone(&header_arc);
-    let header = header_copy.lock().unwrap().clone();
-
-    let client = reqwest::Client::builder()
-        .user_agent(USER_AGENT)
-        .build()
-        .unwrap();
-
+) -> Result<TrackStream> {
     let url = format!(
         "{}/tracks/{}/playbackinfopostpaywall",
         TIDAL_BASE, tracks[index].track_id
     );
-    //println!("url: {url}");
-    let urls = reqwest::Url::parse_with_params(&url, param).unwrap();
-    let resp = client
-        .get(urls)
-        .headers(header.clone())
-        .send()
-        .await
-        .unwrap();
-    //println!("{resp:?}");
-    //println!("{}", resp.status());
-    let result = resp.json::<serde_json::Value>().await.unwrap();
-    
-    //println!("result: {}", result);
-    let manifest = remove_non_alphanumeric(result.get("manifest").unwrap().to_string());
-
-    let d_manifest =
-        String::from_utf8(base64::prelude::BASE64_STANDARD.decode(manifest).unwrap()).unwrap();
-    let d_manifest_json: Value = serde_json::from_str(&d_manifest).unwrap();
-    let d_url = d_manifest_json.get("urls").unwrap().to_string();
-    let d_url = remove_non_alphanumeric(d_url);
-    let mut file_name = format!("{} - {}.flac", tracks[index].artist, tracks[index].title);
-
-    if audio_quality.contains("HIGH") || audio_quality.contains("LOW") {
-        file_name = format!("{} - {}.mp4", tracks[index].artist, tracks[index].title)
+    let resp = t_client.api_get(client, &url, param).await?;
+    let result = resp.json::<PlaybackInfo>().await?;
+
+    let d_manifest = base64::prelude::BASE64_STANDARD
+        .decode(result.manifest)
+        .map_err(|e| Error::Manifest(e.to_string()))?;
+    let (urls, mime_type, codecs, key) = match result.manifest_mime_type.as_str() {
+        "application/vnd.tidal.bts" => {
+            let d_manifest: Manifest = serde_json::from_slice(&d_manifest)
+                .map_err(|e| Error::Manifest(e.to_string()))?;
+            let key = StreamKey::from_manifest(
+                d_manifest.encryption_type.as_deref(),
+                d_manifest.key_id.as_deref(),
+            )?;
+            (
+                d_manifest.urls,
+                d_manifest.mime_type.unwrap_or_default(),
+                d_manifest.codecs.unwrap_or_default(),
+                key,
+            )
+        }
+        "application/dash+xml" => {
+            let d_manifest = String::from_utf8(d_manifest)
+                .map_err(|e| Error::Manifest(e.to_string()))?;
+            let stream = parse_mpd(&d_manifest)?;
+            (stream.urls, stream.mime_type, stream.codecs, None)
+        }
+        other => {
+            return Err(Error::Unsupported(format!(
+                "manifest type {}",
+                other
+            )))
+        }
     };
-    let path = match url_type {
-        UrlType::Playlist => format!("Playlist/{}/{}", tracks[index].artist, tracks[index].album),
-        _ => format!("Album/{}/{}", tracks[index].artist, tracks[index].album)
+    if urls.is_empty() {
+        return Err(Error::Manifest(String::from("no stream url")));
+    }
+    // The mime type names the container: audio/flac is a plain FLAC file, audio/mp4 holds AAC
+    // or (DASH) FLAC. Only streams without a telling mime type are judged by their codec.
+    let extension = if mime_type.contains("mp4") {
+        "m4a"
+    } else if mime_type.contains("flac") || codecs.to_lowercase().contains("flac") {
+        "flac"
+    } else {
+        "m4a"
     };
+    let mut track = tracks[index].clone();
+    if let Some(audio_quality) = result.audio_quality {
+        track.audio_quality = audio_quality;
+    }
+    track.track_gain = result.track_replay_gain.or(track.track_gain);
+    track.track_peak = result.track_peak_amplitude.or(track.track_peak);
+    track.album_gain = result.album_replay_gain;
+    track.album_peak = result.album_peak_amplitude;
+    let config = &t_client.config;
+    if config.embed_lyrics || config.save_lrc {
+        if let Some(lyrics) = get_lyrics(t_client, client, track.track_id).await {
+            track.lyrics = lyrics.lyrics.filter(|lyrics| !lyrics.trim().is_empty());
+            track.synced_lyrics = lyrics.subtitles.filter(|lyrics| !lyrics.trim().is_empty());
+        }
+    }
+    let sanitizer = Sanitizer::new(config);
+    let template = Template::parse(&config.track_template, "track_template", TRACK_FIELDS)?;
+    let rendered = template.render(&|name| track.template_value(name).map(|v| sanitizer.value(&v)));
+    let mut file_name = sanitizer.path(&rendered, Some(extension));
+    if config.disc_folders && track.disc_total.unwrap_or(1) > 1 {
+        file_name = format!("CD{}/{}", track.disc_number, file_name);
+    }
 
-    (d_url, file_name)
-    /*
-
-    let d_file = 
-        .get(d_url)
-        .send()
-        .await
-        .unwrap()
-        .bytes()
-        .await
-        .unwrap();
-    let mut file_name = format!(
-        "Album/{}/{}/{} - {}.flac",
-        tracks[index].artist, tracks[index].album, tracks[index].artist, tracks[index].title
-    );
+    Ok(TrackStream {
+        urls,
+        file_name,
+        key,
+        track,
+    })
+}
 
-    if tracks[index].audio_quality.contains("HIGH") {
-        file_name = format!(
-            "Album/{}/{}/{} - {}.m4a",
-            tracks[index].artist, tracks[index].album, tracks[index].artist, tracks[index].title
-        )
+// Most tracks have no lyrics and TIDAL answers 404 for them, which is not worth reporting
+async fn get_lyrics(t_client: &TidalClient, client: &Client, track_id: u32) -> Option<Lyrics> {
+    let url = format!("{}/tracks/{}/lyrics", TIDAL_BASE, track_id);
+    let country_code = t_client.country_code();
+    let params = [("countryCode", country_code.as_str())];
+    let result = match t_client.api_get(client, &url, &params).await {
+        Ok(resp) => resp.json::<Lyrics>().await.map_err(Error::from),
+        Err(e) => Err(e),
     };
-
-    if Path::new(file_name.as_str()).exists() {
-        println!(
-            "File: {} - {}.flac already exists! skip downloading..",
-            tracks[index].artist, tracks[index].title
-        );
-        return;
+    match result {
+        Ok(lyrics) => Some(lyrics),
+        Err(Error::Http { status, .. }) if status == reqwest::StatusCode::NOT_FOUND => None,
+        Err(e) => {
+            let _ = t_client
+                .progress
+                .println(format!("Cannot fetch lyrics of track {}: {}", track_id, e));
+            None
+        }
     }
-    let mut file = fs::File::create(file_name.clone()).unwrap();
-    file.write(&d_file).unwrap();
-    write_metadata(&tracks[index], client).await;
-    println!("Downloaded: {file_name}");
-    */
 }
@@ -0,0 +1,30 @@
+use reqwest::StatusCode;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("network error: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error("HTTP {status} from {url}")]
+    Http { status: StatusCode, url: String },
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    #[error("invalid stream manifest: {0}")]
+    Manifest(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to write tags: {0}")]
+    Tag(#[from] lofty::LoftyError),
+    #[error("cannot analyze audio: {0}")]
+    Audio(String),
+    #[error("unsupported content: {0}")]
+    Unsupported(String),
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    #[error("{} of {total} tracks failed", failed.len())]
+    Incomplete {
+        total: usize,
+        failed: Vec<(String, Error)>,
+    },
+}
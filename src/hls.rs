@@ -0,0 +1,261 @@
+// Minimal HLS (application/vnd.apple.mpegurl) support for music videos: pick a variant
+// from the master playlist and list the segments of its media playlist.
+use reqwest::Url;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub bandwidth: u64,
+    // (width, height), not every variant states it
+    pub resolution: Option<(u32, u32)>,
+    pub url: String,
+}
+
+pub fn parse_master(playlist: &str, base_url: &str) -> Result<Vec<Variant>> {
+    let base = parse_base(base_url)?;
+    let mut variants = Vec::new();
+    let mut stream_info: Option<Vec<(String, String)>> = None;
+    for line in lines(playlist)? {
+        if let Some(attributes) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            stream_info = Some(parse_attributes(attributes));
+        } else if line.starts_with('#') {
+            continue;
+        } else if let Some(attributes) = stream_info.take() {
+            let attribute = |name: &str| {
+                attributes
+                    .iter()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.as_str())
+            };
+            variants.push(Variant {
+                bandwidth: attribute("BANDWIDTH")
+                    .and_then(|bandwidth| bandwidth.parse().ok())
+                    .unwrap_or(0),
+                resolution: attribute("RESOLUTION").and_then(|resolution| {
+                    let (width, height) = resolution.split_once('x')?;
+                    Some((width.parse().ok()?, height.parse().ok()?))
+                }),
+                url: resolve(&base, line)?,
+            });
+        }
+    }
+    if variants.is_empty() {
+        return Err(Error::Manifest(String::from("no variant in HLS master playlist")));
+    }
+    Ok(variants)
+}
+
+// The best variant no taller than `max_height`, or the smallest one if all are taller.
+// Variants without a resolution are ranked by bandwidth alone.
+pub fn select_variant(variants: &[Variant], max_height: u32) -> Option<&Variant> {
+    let height = |variant: &Variant| variant.resolution.map(|(_, height)| height).unwrap_or(0);
+    variants
+        .iter()
+        .filter(|variant| height(variant) <= max_height)
+        .max_by_key(|variant| (height(variant), variant.bandwidth))
+        .or_else(|| {
+            variants
+                .iter()
+                .min_by_key(|variant| (height(variant), variant.bandwidth))
+        })
+}
+
+#[derive(Debug)]
+pub struct MediaPlaylist {
+    // Segment urls in playback order, preceded by the initialization segment of fMP4 streams
+    pub urls: Vec<String>,
+    // Whether the segments are fragmented MP4 (with an EXT-X-MAP initialization segment)
+    // rather than MPEG-TS
+    pub fmp4: bool,
+}
+
+pub fn parse_media(playlist: &str, base_url: &str) -> Result<MediaPlaylist> {
+    let base = parse_base(base_url)?;
+    let mut urls = Vec::new();
+    let mut fmp4 = false;
+    for line in lines(playlist)? {
+        if let Some(attributes) = line.strip_prefix("#EXT-X-KEY:") {
+            let method = parse_attributes(attributes)
+                .into_iter()
+                .find(|(key, _)| key == "METHOD")
+                .map(|(_, value)| value);
+            if method.as_deref() != Some("NONE") {
+                return Err(Error::Unsupported(String::from("encrypted HLS stream")));
+            }
+        } else if line.starts_with("#EXT-X-BYTERANGE") {
+            return Err(Error::Unsupported(String::from("HLS byte range segments")));
+        } else if let Some(attributes) = line.strip_prefix("#EXT-X-MAP:") {
+            let uri = parse_attributes(attributes)
+                .into_iter()
+                .find(|(key, _)| key == "URI")
+                .map(|(_, value)| value)
+                .ok_or_else(|| Error::Manifest(String::from("EXT-X-MAP without URI")))?;
+            urls.push(resolve(&base, &uri)?);
+            fmp4 = true;
+        } else if line.starts_with("#EXT-X-STREAM-INF") {
+            return Err(Error::Manifest(String::from(
+                "expected a media playlist, got a master playlist",
+            )));
+        } else if !line.starts_with('#') {
+            urls.push(resolve(&base, line)?);
+        }
+    }
+    if urls.is_empty() {
+        return Err(Error::Manifest(String::from("no segment in HLS media playlist")));
+    }
+    Ok(MediaPlaylist { urls, fmp4 })
+}
+
+// Non-empty lines after the #EXTM3U header
+fn lines(playlist: &str) -> Result<impl Iterator<Item = &str>> {
+    let mut lines = playlist.lines().map(|line| line.trim()).filter(|line| !line.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        return Err(Error::Manifest(String::from("not an HLS playlist")));
+    }
+    Ok(lines)
+}
+
+// Attribute lists such as BANDWIDTH=1280000,CODECS="avc1.4d401f,mp4a.40.2",RESOLUTION=640x360
+fn parse_attributes(attributes: &str) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    let mut rest = attributes;
+    while let Some((key, value)) = rest.split_once('=') {
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((value, next)) => (value, next.trim_start_matches(',')),
+                None => (quoted, ""),
+            },
+            None => match value.split_once(',') {
+                Some((value, next)) => (value, next),
+                None => (value, ""),
+            },
+        };
+        parsed.push((key.trim().to_string(), value.to_string()));
+        rest = next;
+    }
+    parsed
+}
+
+fn parse_base(base_url: &str) -> Result<Url> {
+    Url::parse(base_url).map_err(|e| Error::Manifest(format!("{}: {}", base_url, e)))
+}
+
+fn resolve(base: &Url, url: &str) -> Result<String> {
+    base.join(url)
+        .map(|url| url.to_string())
+        .map_err(|e| Error::Manifest(format!("{}: {}", url, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "https://example.com/video/master.m3u8";
+
+    fn variant(height: Option<u32>, bandwidth: u64) -> Variant {
+        Variant {
+            bandwidth,
+            resolution: height.map(|height| (height * 16 / 9, height)),
+            url: format!("{}p-{}.m3u8", height.unwrap_or(0), bandwidth),
+        }
+    }
+
+    #[test]
+    fn master_with_quoted_codecs() {
+        let playlist = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,CODECS=\"avc1.4d401f,mp4a.40.2\",RESOLUTION=640x360
+360/index.m3u8
+#EXT-X-STREAM-INF:CODECS=\"avc1.640028,mp4a.40.2\",RESOLUTION=1920x1080,BANDWIDTH=6000000
+https://cdn.example.com/1080/index.m3u8
+";
+        let variants = parse_master(playlist, BASE).unwrap();
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].bandwidth, 1280000);
+        assert_eq!(variants[0].resolution, Some((640, 360)));
+        assert_eq!(variants[0].url, "https://example.com/video/360/index.m3u8");
+        assert_eq!(variants[1].bandwidth, 6000000);
+        assert_eq!(variants[1].resolution, Some((1920, 1080)));
+        assert_eq!(variants[1].url, "https://cdn.example.com/1080/index.m3u8");
+    }
+
+    #[test]
+    fn select_at_most_max_height() {
+        let variants = [
+            variant(Some(360), 1000),
+            variant(Some(720), 3000),
+            variant(Some(720), 4000),
+            variant(Some(1080), 6000),
+        ];
+        assert_eq!(select_variant(&variants, 720).unwrap().bandwidth, 4000);
+        assert_eq!(select_variant(&variants, 1080).unwrap().bandwidth, 6000);
+        assert_eq!(select_variant(&variants, 480).unwrap().bandwidth, 1000);
+    }
+
+    #[test]
+    fn select_smallest_when_all_taller() {
+        let variants = [variant(Some(1080), 6000), variant(Some(720), 3000)];
+        assert_eq!(select_variant(&variants, 240).unwrap().bandwidth, 3000);
+        assert!(select_variant(&[], 240).is_none());
+    }
+
+    #[test]
+    fn media_with_map_and_unencrypted_key() {
+        let playlist = "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-KEY:METHOD=NONE
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXTINF:4.0,
+seg0.m4s
+#EXTINF:4.0,
+seg1.m4s
+#EXT-X-ENDLIST
+";
+        let media = parse_media(playlist, BASE).unwrap();
+        assert!(media.fmp4);
+        assert_eq!(
+            media.urls,
+            [
+                "https://example.com/video/init.mp4",
+                "https://example.com/video/seg0.m4s",
+                "https://example.com/video/seg1.m4s",
+            ]
+        );
+    }
+
+    #[test]
+    fn media_mpeg_ts() {
+        let playlist = "#EXTM3U
+#EXTINF:4.0,
+seg0.ts
+#EXTINF:4.0,
+https://cdn.example.com/seg1.ts
+";
+        let media = parse_media(playlist, BASE).unwrap();
+        assert!(!media.fmp4);
+        assert_eq!(
+            media.urls,
+            ["https://example.com/video/seg0.ts", "https://cdn.example.com/seg1.ts"]
+        );
+    }
+
+    #[test]
+    fn media_encrypted_is_rejected() {
+        let playlist = "#EXTM3U
+#EXT-X-KEY:METHOD=AES-128,URI=\"https://example.com/key\"
+#EXTINF:4.0,
+seg0.ts
+";
+        assert!(matches!(parse_media(playlist, BASE), Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn media_byte_range_is_rejected() {
+        let playlist = "#EXTM3U
+#EXTINF:4.0,
+#EXT-X-BYTERANGE:1000@0
+video.ts
+";
+        assert!(matches!(parse_media(playlist, BASE), Err(Error::Unsupported(_))));
+    }
+}
@@ -0,0 +1,243 @@
+// Local ReplayGain 2.0 analysis: decode the file, measure EBU R128 integrated loudness and
+// true peak, and tag the gain needed to reach the -18 LUFS reference level.
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use ebur128::{EbuR128, Mode};
+use lofty::{
+    mp4::{Atom, AtomData, AtomIdent, Ilst, Mp4File},
+    AudioFile, FileType, ItemKey, ParseOptions, Probe, Tag, TagExt, TaggedFileExt,
+};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::DecoderOptions,
+    errors::Error as DecodeError,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use crate::error::{Error, Result};
+
+const REFERENCE_LOUDNESS: f64 = -18.0;
+const AUDIO_EXTENSIONS: &[&str] = &["flac", "m4a"];
+
+// Files that could not be analyzed or tagged, with the reason
+pub type Failures = Vec<(PathBuf, Error)>;
+
+struct Measurement {
+    meter: EbuR128,
+    peak: f64,
+}
+
+impl Measurement {
+    fn gain(&self) -> Result<f64> {
+        Ok(REFERENCE_LOUDNESS - self.meter.loudness_global().map_err(audio_error)?)
+    }
+}
+
+// Analyze one album. The album gain is only written when every track was measured,
+// otherwise it would not describe the whole album.
+pub fn analyze_album(paths: &[PathBuf], album_gain: bool) -> Failures {
+    let mut failures = Failures::new();
+    let mut measured = Vec::new();
+    for path in paths {
+        match measure(path) {
+            Ok(measurement) => measured.push((path, measurement)),
+            Err(e) => failures.push((path.clone(), e)),
+        }
+    }
+    let album = if album_gain && failures.is_empty() && !measured.is_empty() {
+        let loudness = EbuR128::loudness_global_multiple(measured.iter().map(|(_, m)| &m.meter));
+        match loudness {
+            Ok(loudness) => {
+                let peak = measured.iter().map(|(_, m)| m.peak).fold(0.0, f64::max);
+                Some((REFERENCE_LOUDNESS - loudness, peak))
+            }
+            Err(e) => {
+                eprintln!("Cannot compute album loudness: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    for (path, measurement) in measured {
+        let result = measurement
+            .gain()
+            .and_then(|gain| write_tags(path, (gain, measurement.peak), album));
+        if let Err(e) = result {
+            failures.push((path.clone(), e));
+        }
+    }
+    failures
+}
+
+// Analyze every FLAC/M4A file below `folder`. Each directory is one album, except that
+// `CD1`, `CD2`, ... subfolders (see disc_folders) belong to the album of their parent.
+pub fn analyze_folder(folder: &Path) -> Result<Failures> {
+    let mut albums: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    collect_files(folder, &mut albums)?;
+    let mut failures = Failures::new();
+    for (album, mut paths) in albums {
+        paths.sort();
+        println!("Analyzing {} ({} tracks)", album.display(), paths.len());
+        failures.extend(analyze_album(&paths, true));
+    }
+    Ok(failures)
+}
+
+// FLAC/M4A files below `dir`, grouped by album folder as analyze_folder treats them
+pub fn collect_files(dir: &Path, albums: &mut BTreeMap<PathBuf, Vec<PathBuf>>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, albums)?;
+            continue;
+        }
+        let is_audio = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+            .unwrap_or(false);
+        if is_audio {
+            albums.entry(album_dir(dir)).or_default().push(path);
+        }
+    }
+    Ok(())
+}
+
+fn album_dir(dir: &Path) -> PathBuf {
+    let is_disc_folder = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("CD"))
+        .map(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+        .unwrap_or(false);
+    match (is_disc_folder, dir.parent()) {
+        (true, Some(parent)) => parent.to_path_buf(),
+        _ => dir.to_path_buf(),
+    }
+}
+
+fn measure(path: &Path) -> Result<Measurement> {
+    let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(audio_error)?;
+    let mut format = probed.format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| Error::Audio(String::from("no audio track")))?;
+    let track_id = track.id;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(audio_error)?;
+
+    // Created from the first decoded packet, which knows the real channel count and rate
+    let mut meter: Option<EbuR128> = None;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => return Err(audio_error(e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt packet only loses a few milliseconds of audio
+            Err(DecodeError::DecodeError(_)) => continue,
+            Err(e) => return Err(audio_error(e)),
+        };
+        let spec = *decoded.spec();
+        if meter.is_none() {
+            let channels = spec.channels.count() as u32;
+            meter = Some(
+                EbuR128::new(channels, spec.rate, Mode::I | Mode::TRUE_PEAK)
+                    .map_err(audio_error)?,
+            );
+        }
+        if samples
+            .as_ref()
+            .is_none_or(|buffer| buffer.capacity() < decoded.capacity() * spec.channels.count())
+        {
+            samples = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+        if let (Some(meter), Some(buffer)) = (&mut meter, &mut samples) {
+            buffer.copy_interleaved_ref(decoded);
+            meter.add_frames_f32(buffer.samples()).map_err(audio_error)?;
+        }
+    }
+    let meter = meter.ok_or_else(|| Error::Audio(String::from("no audio decoded")))?;
+    let mut peak: f64 = 0.0;
+    for channel in 0..meter.channels() {
+        peak = peak.max(meter.true_peak(channel).map_err(audio_error)?);
+    }
+    Ok(Measurement { meter, peak })
+}
+
+// Replaces whatever ReplayGain values the file already has, including TIDAL's
+fn write_tags(path: &Path, track: (f64, f64), album: Option<(f64, f64)>) -> Result<()> {
+    let mut values = vec![
+        (ItemKey::ReplayGainTrackGain, "replaygain_track_gain", format!("{:.2} dB", track.0)),
+        (ItemKey::ReplayGainTrackPeak, "replaygain_track_peak", format!("{:.6}", track.1)),
+    ];
+    if let Some((gain, peak)) = album {
+        values.push((ItemKey::ReplayGainAlbumGain, "replaygain_album_gain", format!("{:.2} dB", gain)));
+        values.push((ItemKey::ReplayGainAlbumPeak, "replaygain_album_peak", format!("{:.6}", peak)));
+    }
+
+    if Probe::open(path)?.guess_file_type()?.file_type() == Some(FileType::Mp4) {
+        // Edit the ilst atoms directly, going through the generic Tag would turn the
+        // binary rtng (explicit) atom into text
+        let mut file = File::open(path)?;
+        let mut mp4 = Mp4File::read_from(&mut file, ParseOptions::new())?;
+        let mut ilst = mp4.remove_ilst().unwrap_or_else(Ilst::new);
+        for (_, name, value) in values {
+            ilst.replace_atom(Atom::new(
+                AtomIdent::Freeform {
+                    mean: Cow::Borrowed("com.apple.iTunes"),
+                    name: Cow::Borrowed(name),
+                },
+                AtomData::UTF8(value),
+            ));
+        }
+        ilst.save_to_path(path)?;
+        return Ok(());
+    }
+
+    let mut tagged_file = Probe::open(path)?.read()?;
+    if tagged_file.primary_tag().is_none() {
+        tagged_file.insert_tag(Tag::new(tagged_file.primary_tag_type()));
+    }
+    if let Some(tag) = tagged_file.primary_tag_mut() {
+        for (key, _, value) in values {
+            tag.insert_text(key, value);
+        }
+        tag.save_to_path(path)?;
+    }
+    Ok(())
+}
+
+fn audio_error(e: impl std::fmt::Display) -> Error {
+    Error::Audio(e.to_string())
+}
@@ -1,11 +1,27 @@
+mod archive;
 mod config;
 mod constants;
+mod cover;
+mod dash;
+mod decrypt;
 mod download;
+mod error;
+mod hls;
+mod loudness;
+mod models;
+mod retry;
+mod sanitize;
+mod template;
+mod throttle;
 mod tidal_client;
+mod video;
 
-use std::env;
+use std::{collections::HashSet, env, process::ExitCode, sync::Arc};
 
-use crate::constants::*;
+use crate::archive::Archive;
+use crate::config::{AudioQuality, Config};
+use crate::error::{Error, Result};
+use crate::throttle::Throttle;
 use crate::tidal_client::*;
 use download::*;
 
@@ -15,6 +31,7 @@ pub enum UrlType {
     Album,
     Artist,
     Playlist,
+    Video,
 }
 
 pub enum UrlId {
@@ -23,33 +40,290 @@ pub enum UrlId {
 }
 
 #[tokio::main(flavor = "multi_thread")]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> ExitCode {
     let args: Vec<String> = env::args().collect();
-    assert!(args.len() > 1);
+    if args.len() < 2 {
+        eprintln!("usage: {} [--max-bandwidth <RATE>] <URL1> <URL2> ...", args[0]);
+        eprintln!("       {} analyze <FOLDER1> <FOLDER2> ...", args[0]);
+        eprintln!("       {} {}", args[0], ARCHIVE_USAGE);
+        return ExitCode::FAILURE;
+    }
+    if args[1] == "analyze" {
+        return analyze(&args[2..]);
+    }
+    if args[1] == "archive" {
+        return match archive_command(&args[2..]).await {
+            Ok(code) => code,
+            Err(e) => {
+                eprintln!("{}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    let (urls, max_bandwidth) = match parse_args(&args[1..]) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    let token = get_token().await;
-    let request = reqwest::Client::builder()
-        .user_agent(USER_AGENT)
-        .build()
-        .unwrap();
+    let mut client = match login().await {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    // Only for this run, the config keeps its own limit
+    if let Some(rate) = max_bandwidth {
+        client.throttle = Arc::new(Throttle::with_rate(rate));
+    }
+    let request = match retry::client(&client.config) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    // Urls are downloaded side by side, the download and API slots of `client` bound how much
+    // runs at once across all of them
+    let results = futures::future::join_all(
+        urls.iter()
+            .map(|url| download_url(&client, &request, url)),
+    )
+    .await;
+    let mut failed_urls = 0;
+    for (url, result) in urls.iter().zip(results) {
+        if let Err(e) = result {
+            failed_urls += 1;
+            eprintln!("Failed to download {}: {}", url, e);
+            if let Error::Incomplete { failed, .. } = &e {
+                for (title, e) in failed {
+                    eprintln!("  {}: {}", title, e);
+                }
+            }
+        }
+    }
+
+    if failed_urls > 0 {
+        eprintln!("{} of {} urls failed", failed_urls, urls.len());
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+// Split the arguments into urls and the --max-bandwidth option, which overrides
+// max_bandwidth and bandwidth_schedule
+fn parse_args(args: &[String]) -> Result<(Vec<String>, Option<throttle::Rate>)> {
+    let mut urls = Vec::new();
+    let mut max_bandwidth = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--max-bandwidth" {
+            let rate = args
+                .next()
+                .ok_or_else(|| Error::Config(String::from("--max-bandwidth needs a rate")))?;
+            max_bandwidth = Some(throttle::parse_rate(rate)?);
+        } else {
+            urls.push(arg.clone());
+        }
+    }
+    if urls.is_empty() {
+        return Err(Error::Config(String::from("no url given")));
+    }
+    Ok((urls, max_bandwidth))
+}
+
+// Write ReplayGain tags measured locally to every album below the given folders
+fn analyze(folders: &[String]) -> ExitCode {
+    if folders.is_empty() {
+        eprintln!("analyze needs at least one folder");
+        return ExitCode::FAILURE;
+    }
+    let mut failed = 0;
+    for folder in folders {
+        match loudness::analyze_folder(std::path::Path::new(folder)) {
+            Ok(failures) => {
+                for (path, e) in &failures {
+                    eprintln!("  {}: {}", path.display(), e);
+                }
+                failed += failures.len();
+            }
+            Err(e) => {
+                eprintln!("Failed to analyze {}: {}", folder, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        eprintln!("{} files could not be analyzed", failed);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+const ARCHIVE_USAGE: &str = "archive list | prune | import [--quality <QUALITY>] <FOLDER1> ...";
+
+// Show or maintain the download archive, which needs no login
+async fn archive_command(args: &[String]) -> Result<ExitCode> {
+    let config = get_token().await?.map(|(_, config)| config).unwrap_or_else(Config::new);
+    if config.download_archive.is_empty() {
+        return Err(Error::Config(String::from(
+            "download_archive is not set in .tdlrs.json",
+        )));
+    }
+    let archive = Archive::open(std::path::Path::new(&config.download_archive))?;
+    match args.first().map(|command| command.as_str()) {
+        Some("list") => {
+            for entry in archive.entries() {
+                let date = chrono::DateTime::from_timestamp(entry.timestamp as i64, 0)
+                    .map(|date| date.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default();
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    entry.track_id,
+                    entry.quality,
+                    entry.size,
+                    date,
+                    entry.path.display()
+                );
+            }
+        }
+        Some("prune") => {
+            let pruned = archive.prune()?;
+            for entry in &pruned {
+                println!("Removed {} ({})", entry.path.display(), entry.track_id);
+            }
+            println!("{} entries removed", pruned.len());
+        }
+        Some("import") if args.len() > 1 => {
+            // Files are recorded in the quality they are unless --quality (an audio_quality
+            // value) says otherwise. Files without the TIDAL_TRACK_ID tag (e.g. downloaded by
+            // older versions) are only reported.
+            let (quality, folders) = match args[1].as_str() {
+                "--quality" if args.len() > 3 => {
+                    let quality = args[2].parse::<AudioQuality>().map_err(Error::Config)?;
+                    (Some(quality.api_name()), &args[3..])
+                }
+                "--quality" => return Err(Error::Config(format!("usage: {}", ARCHIVE_USAGE))),
+                _ => (None, &args[1..]),
+            };
+            for folder in folders {
+                let (imported, skipped) = archive.import(std::path::Path::new(folder), quality)?;
+                for (path, e) in &skipped {
+                    eprintln!("  {}: {}", path.display(), e);
+                }
+                println!("{}: {} tracks imported, {} skipped", folder, imported, skipped.len());
+            }
+        }
+        _ => {
+            return Err(Error::Config(format!("usage: {}", ARCHIVE_USAGE)))
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn login() -> Result<TidalClient> {
+    let token = get_token().await?;
     let mut client = TidalClient::new(token);
-    client.get_session("zU4XHVVkc2tDPo4t".to_string()).await;
-    client.login_session().await;
-    client.save_token().await;
-    for i in 1..args.len() {
-        let (id, url_type) = parse_url(args.get(i).unwrap());
-        let tracks = get_tracks_from_id(&client, request.clone(), id, &url_type).await;
-        download(&client, &tracks.0, &url_type, tracks.1).await;
+    if client.have_userinfo && client.is_token_expired() {
+        if let Err(e) = client.refresh_access_token().await {
+            println!("Failed to refresh session token ({})\nLogin again..", e);
+            client.have_userinfo = false;
+        }
     }
+    client.get_session("zU4XHVVkc2tDPo4t".to_string()).await?;
+    client.login_session().await?;
+    client.save_token().await?;
+    client.open_archive()?;
+    Ok(client)
+}
 
-    Ok(())
+async fn download_url(client: &TidalClient, request: &reqwest::Client, url: &str) -> Result<()> {
+    let (id, url_type) = parse_url(url)?;
+    if url_type == UrlType::Artist {
+        let UrlId::Primary(artist_id) = id else {
+            return Err(Error::Unsupported(format!("invalid artist url {}", url)));
+        };
+        let album_ids = get_albums_from_artist(client, request.clone(), artist_id).await?;
+        let results = futures::future::join_all(album_ids.iter().map(|&album_id| async move {
+            let listing = match get_tracks_from_id(
+                client,
+                request.clone(),
+                UrlId::Primary(album_id),
+                &UrlType::Album,
+            )
+            .await
+            {
+                Ok(listing) => listing,
+                Err(e) => return (Err(e), Vec::new()),
+            };
+            let video_ids: Vec<u32> = listing.videos.iter().map(|video| video.id).collect();
+            let total = listing.tracks.len() + video_ids.len();
+            let result = download(client, request, &listing).await.map(|()| total);
+            (result, video_ids)
+        }))
+        .await;
+        let mut total = 0;
+        let mut failed = Vec::new();
+        // Videos that are part of an album went into its folder already
+        let mut album_videos = HashSet::new();
+        for (album_id, (result, video_ids)) in album_ids.into_iter().zip(results) {
+            album_videos.extend(video_ids);
+            match result {
+                Ok(tracks) => total += tracks,
+                Err(Error::Incomplete {
+                    total: tracks,
+                    failed: failed_tracks,
+                }) => {
+                    total += tracks;
+                    failed.extend(failed_tracks);
+                }
+                Err(e) => failed.push((format!("album {}", album_id), e)),
+            }
+        }
+        if client.config.download_videos {
+            let result = match video::get_artist_videos(client, request, artist_id).await {
+                Ok(mut videos) => {
+                    videos.retain(|video| !album_videos.contains(&video.id));
+                    get_artist(client, request, artist_id)
+                        .await
+                        .and_then(|artist| artist_folder(&client.config, &artist.name))
+                        .map(|dir| (videos, dir))
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok((videos, dir)) => {
+                    total += videos.len();
+                    failed.extend(video::download_videos(client, request, &videos, &dir).await);
+                }
+                Err(e) => failed.push((format!("videos of artist {}", artist_id), e)),
+            }
+        }
+        if !failed.is_empty() {
+            return Err(Error::Incomplete { total, failed });
+        }
+        return Ok(());
+    }
+    if url_type == UrlType::Video {
+        let UrlId::Primary(video_id) = id else {
+            return Err(Error::Unsupported(format!("invalid video url {}", url)));
+        };
+        let video = video::get_video(client, request, video_id).await?;
+        return video::download_video(client, request, &video, &client.config.download_path).await;
+    }
+    let listing = get_tracks_from_id(client, request.clone(), id, &url_type).await?;
+    download(client, request, &listing).await
 }
 
-fn parse_url(url: &str) -> (UrlId, UrlType) {
-    if !url.clone().contains("tidal.com") {
-        panic!("invalid url ");
+fn parse_url(url: &str) -> Result<(UrlId, UrlType)> {
+    let invalid = || Error::Unsupported(format!("invalid url {}", url));
+    if !url.contains("tidal.com") {
+        return Err(invalid());
     }
-    let (_, id) = url.rsplit_once("/").unwrap();
+    let (_, id) = url.rsplit_once('/').ok_or_else(invalid)?;
     let album_id_option = id.parse::<u32>();
 
     let mut url_type = UrlType::Track;
@@ -66,6 +340,12 @@ fn parse_url(url: &str) -> (UrlId, UrlType) {
     if url.contains("playlist") {
         url_type = UrlType::Playlist;
     }
+    if url.contains("artist") {
+        url_type = UrlType::Artist;
+    }
+    if url.contains("video") {
+        url_type = UrlType::Video;
+    }
 
-    (url_id, url_type)
+    Ok((url_id, url_type))
 }
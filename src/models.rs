@@ -0,0 +1,238 @@
+// Typed TIDAL API responses. Fields TIDAL leaves out or sends as null are optional.
+
+use serde::{de::Error as _, Deserializer};
+use serde_derive::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Artist {
+    pub id: u32,
+    pub name: String,
+    pub picture: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Album {
+    pub id: u32,
+    pub title: String,
+    pub cover: Option<String>,
+    pub artist: Option<Artist>,
+    // Only present on the full /albums/{id} response, not on the album stub inside a track
+    pub number_of_tracks: Option<u32>,
+    pub number_of_volumes: Option<u32>,
+    pub release_date: Option<String>,
+    pub upc: Option<String>,
+    pub copyright: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Track {
+    pub id: u32,
+    pub title: String,
+    pub track_number: u32,
+    #[serde(default = "first_volume")]
+    pub volume_number: u32,
+    #[serde(default)]
+    pub explicit: bool,
+    pub audio_quality: Option<String>,
+    pub isrc: Option<String>,
+    pub copyright: Option<String>,
+    // e.g. "Remastered 2011" or "Live", shown after the title in TIDAL
+    pub version: Option<String>,
+    pub replay_gain: Option<f64>,
+    pub peak: Option<f64>,
+    pub artist: Option<Artist>,
+    #[serde(default)]
+    pub artists: Vec<Artist>,
+    pub album: Album,
+}
+
+fn first_volume() -> u32 {
+    1
+}
+
+impl Track {
+    // Main artist, falling back to the first credited artist when TIDAL omits `artist`
+    pub fn artist_name(&self) -> String {
+        self.artist
+            .as_ref()
+            .or(self.artists.first())
+            .map(|artist| artist.name.clone())
+            .unwrap_or_else(|| String::from("Unknown Artist"))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Video {
+    pub id: u32,
+    pub title: String,
+    #[serde(default)]
+    pub explicit: bool,
+    pub release_date: Option<String>,
+    pub stream_start_date: Option<String>,
+    // e.g. MP4_1080P
+    pub quality: Option<String>,
+    pub artist: Option<Artist>,
+    #[serde(default)]
+    pub artists: Vec<Artist>,
+}
+
+impl Video {
+    pub fn artist_name(&self) -> String {
+        self.artist
+            .as_ref()
+            .or(self.artists.first())
+            .map(|artist| artist.name.clone())
+            .unwrap_or_else(|| String::from("Unknown Artist"))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Playlist {
+    pub title: String,
+    pub square_image: Option<String>,
+    pub image: Option<String>,
+}
+
+// Entry of /albums/{id}/items and /playlists/{id}/items. /albums/{id}/items/credits adds
+// the credits of each track.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Item {
+    #[serde(flatten)]
+    pub item: MediaItem,
+    #[serde(default)]
+    pub credits: Vec<Credit>,
+}
+
+// Listings mix tracks and videos, told apart by `type`
+#[derive(Debug, Clone)]
+pub enum MediaItem {
+    Track(Box<Track>),
+    Video(Video),
+    // Any other type TIDAL may list
+    Other,
+}
+
+impl<'de> serde::Deserialize<'de> for MediaItem {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Tagged {
+            #[serde(rename = "type")]
+            item_type: String,
+            item: serde_json::Value,
+        }
+        // A malformed track is still an error rather than something to skip
+        let tagged = Tagged::deserialize(deserializer)?;
+        match tagged.item_type.as_str() {
+            "track" => serde_json::from_value(tagged.item)
+                .map(|track| MediaItem::Track(Box::new(track)))
+                .map_err(D::Error::custom),
+            "video" => serde_json::from_value(tagged.item)
+                .map(MediaItem::Video)
+                .map_err(D::Error::custom),
+            _ => Ok(MediaItem::Other),
+        }
+    }
+}
+
+// One role of a track's credits, e.g. "Composer" with everyone credited for it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Credit {
+    #[serde(rename = "type")]
+    pub credit_type: String,
+    #[serde(default)]
+    pub contributors: Vec<Contributor>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Contributor {
+    pub name: String,
+}
+
+// One page of a paginated listing
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Page<T> {
+    #[serde(default = "Vec::new")]
+    pub items: Vec<T>,
+    #[serde(default)]
+    pub total_number_of_items: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaybackInfo {
+    pub manifest_mime_type: String,
+    pub manifest: String,
+    pub audio_quality: Option<String>,
+    pub track_replay_gain: Option<f64>,
+    pub track_peak_amplitude: Option<f64>,
+    pub album_replay_gain: Option<f64>,
+    pub album_peak_amplitude: Option<f64>,
+}
+
+// Playback info of /videos/{id}/playbackinfopostpaywall. The application/vnd.tidal.emu
+// manifest decodes to a `Manifest` whose url is an HLS master playlist.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoPlaybackInfo {
+    pub manifest_mime_type: String,
+    pub manifest: String,
+    pub video_quality: Option<String>,
+}
+
+// Response of /tracks/{id}/lyrics. `subtitles` are the time-synced lyrics in LRC format.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lyrics {
+    pub lyrics: Option<String>,
+    pub subtitles: Option<String>,
+}
+
+// Decoded `manifest` of PlaybackInfo when `manifestMimeType` is application/vnd.tidal.bts
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub mime_type: Option<String>,
+    pub codecs: Option<String>,
+    pub encryption_type: Option<String>,
+    pub key_id: Option<String>,
+    pub urls: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenUser {
+    pub user_id: u64,
+    pub country_code: String,
+}
+
+// Response of /oauth2/token, both for the device code and the refresh token grant
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_in: u64,
+    pub user: Option<TokenUser>,
+}
+
+// Error body of /oauth2/token, e.g. `authorization_pending` while polling the device code
+#[derive(Debug, Deserialize)]
+pub struct AuthError {
+    pub status: u16,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TokenResult {
+    Token(TokenResponse),
+    Error(AuthError),
+}
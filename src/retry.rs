@@ -0,0 +1,135 @@
+// Retrying of transient failures: connection errors, timeouts, 429 Too Many Requests and
+// 5xx answers. The same policy covers TIDAL API calls, stream downloads and image fetches.
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::ErrorKind,
+    time::{Duration, SystemTime},
+};
+
+use reqwest::{header::RETRY_AFTER, Client, RequestBuilder, Response, StatusCode};
+
+use crate::config::Config;
+use crate::constants::USER_AGENT;
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // Tries in total, 1 disables retrying
+    pub attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // How long a try may wait for the response headers
+    pub timeout: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &Config) -> RetryPolicy {
+        RetryPolicy {
+            attempts: config.retry_attempts.max(1),
+            base_delay: Duration::from_millis(config.retry_delay_ms),
+            max_delay: Duration::from_millis(config.retry_max_delay_ms),
+            timeout: Duration::from_millis(config.response_timeout_ms),
+        }
+    }
+
+    // Send the request, sending it again while it fails transiently. The last response is
+    // returned whatever its status, callers still check it.
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let mut request = request;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            // Requests with a streaming body can't be cloned, and so not be retried
+            let next = match request.try_clone() {
+                Some(next) if attempt < self.attempts => next,
+                _ => return self.send_once(request).await,
+            };
+            let delay = match self.send_once(request).await {
+                Ok(resp) if is_retryable_status(resp.status()) => retry_after(&resp)
+                    .map(|delay| delay.min(self.max_delay))
+                    .unwrap_or_else(|| self.delay(attempt)),
+                Ok(resp) => return Ok(resp),
+                Err(e) if is_transient(&e) => self.delay(attempt),
+                Err(e) => return Err(e),
+            };
+            tokio::time::sleep(delay).await;
+            request = next;
+        }
+    }
+
+    // A server that accepts the connection but never answers fails the try as timed out.
+    // Only the headers are waited for, streams guard their body themselves.
+    async fn send_once(&self, request: RequestBuilder) -> Result<Response> {
+        match tokio::time::timeout(self.timeout, request.send()).await {
+            Ok(resp) => Ok(resp?),
+            Err(_) => Err(Error::Io(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("no response within {}ms", self.timeout.as_millis()),
+            ))),
+        }
+    }
+
+    // How long to wait after the `attempt`th failed try: the base delay doubled for every
+    // earlier try, capped at max_delay, of which a random part is taken so that parallel
+    // downloads don't all retry at the same moment
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        exponential / 2 + exponential.mul_f64(jitter() / 2.0)
+    }
+}
+
+// HTTP client for TIDAL and its CDNs. A whole-request timeout would cut long stream
+// transfers, so only connecting is bounded here and the answer by RetryPolicy::send.
+pub fn client(config: &Config) -> Result<Client> {
+    Ok(Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+        .build()?)
+}
+
+// Whether `e` is worth another try: the connection broke or stalled, or the server is
+// overloaded. Anything else (404, bad manifest, full disk...) fails the same way again.
+pub fn is_transient(e: &Error) -> bool {
+    match e {
+        Error::Network(e) => is_retryable_request(e) || e.is_body(),
+        Error::Http { status, .. } => is_retryable_status(*status),
+        Error::Io(e) => matches!(
+            e.kind(),
+            ErrorKind::TimedOut
+                | ErrorKind::UnexpectedEof
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+        ),
+        _ => false,
+    }
+}
+
+fn is_retryable_request(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout() || e.is_request()
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Retry-After is either a number of seconds or an HTTP date
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+// A random number in [0, 1), RandomState is seeded randomly for every instance
+fn jitter() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
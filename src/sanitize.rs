@@ -0,0 +1,199 @@
+// Makes generated names safe to use as path components, including on FAT/exFAT/SMB shares
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+
+// Characters Windows (and therefore FAT/exFAT/SMB) refuses in file names
+const WINDOWS_ILLEGAL: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+// Appended to files until their download is complete, see download::part_path
+const PART_SUFFIX: &str = ".part";
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug, Clone)]
+pub struct Sanitizer {
+    replacement: String,
+    max_length: usize,
+    windows_compatible: bool,
+}
+
+impl Sanitizer {
+    pub fn new(config: &Config) -> Sanitizer {
+        Sanitizer {
+            replacement: config.replacement_char.clone(),
+            max_length: config.max_name_length,
+            windows_compatible: config.windows_compatible,
+        }
+    }
+
+    pub fn validate(config: &Config) -> Result<()> {
+        let sanitizer = Sanitizer::new(config);
+        if sanitizer.replacement.chars().any(|c| sanitizer.is_illegal(c)) {
+            return Err(Error::Config(format!(
+                "replacement_char \"{}\" is not allowed in file names",
+                config.replacement_char
+            )));
+        }
+        if config.max_name_length < 16 {
+            return Err(Error::Config(String::from(
+                "max_name_length must be at least 16 bytes",
+            )));
+        }
+        Ok(())
+    }
+
+    fn is_illegal(&self, c: char) -> bool {
+        c == '/'
+            || c == '\\'
+            || c.is_control()
+            || (self.windows_compatible && WINDOWS_ILLEGAL.contains(&c))
+    }
+
+    // A value substituted into a template, e.g. a title: it must never introduce a
+    // path separator, so "AC/DC Live" stays one component
+    pub fn value(&self, value: &str) -> String {
+        value
+            .nfc()
+            .map(|c| {
+                if self.is_illegal(c) {
+                    self.replacement.clone()
+                } else {
+                    c.to_string()
+                }
+            })
+            .collect()
+    }
+
+    // A rendered template: every `/`-separated component is cleaned up on its own.
+    // `extension` is appended to the last component and counted against max_name_length,
+    // together with the ".part" suffix the file has while it is downloaded.
+    pub fn path(&self, rendered: &str, extension: Option<&str>) -> String {
+        let components: Vec<&str> = rendered.split('/').filter(|c| !c.trim().is_empty()).collect();
+        let last = components.len().saturating_sub(1);
+        let mut out: Vec<String> = Vec::new();
+        for (i, component) in components.iter().enumerate() {
+            match (i == last, extension) {
+                (true, Some(extension)) => {
+                    let max_length = self
+                        .max_length
+                        .saturating_sub(extension.len() + 1 + PART_SUFFIX.len());
+                    out.push(format!(
+                        "{}.{}",
+                        self.component(component, max_length),
+                        extension
+                    ));
+                }
+                _ => out.push(self.component(component, self.max_length)),
+            }
+        }
+        if out.is_empty() {
+            out.push(self.component("", self.max_length));
+        }
+        out.join("/")
+    }
+
+    fn component(&self, component: &str, max_length: usize) -> String {
+        let mut name = self.trim(component.nfc().collect::<String>().as_str());
+        if name.len() > max_length {
+            let mut end = max_length;
+            while !name.is_char_boundary(end) {
+                end -= 1;
+            }
+            name = self.trim(&name[..end]);
+        }
+        if name.is_empty() || name == "." || name == ".." {
+            return self.replacement.clone();
+        }
+        if self.windows_compatible {
+            let stem = name.split('.').next().unwrap_or("").trim_end();
+            if WINDOWS_RESERVED
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+            {
+                name.insert_str(stem.len(), &self.replacement);
+            }
+        }
+        name
+    }
+
+    // Windows silently drops trailing dots and spaces, which breaks exist checks
+    fn trim(&self, name: &str) -> String {
+        let name = name.trim();
+        if self.windows_compatible {
+            name.trim_end_matches(['.', ' ']).to_string()
+        } else {
+            name.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitizer(max_name_length: usize, windows_compatible: bool) -> Sanitizer {
+        let mut config = Config::new();
+        config.max_name_length = max_name_length;
+        config.windows_compatible = windows_compatible;
+        Sanitizer::new(&config)
+    }
+
+    #[test]
+    fn value_never_adds_a_folder() {
+        let sanitizer = sanitizer(200, true);
+        let rendered = format!("Album/{}", sanitizer.value("AC/DC Live"));
+        assert_eq!(sanitizer.path(&rendered, None), "Album/AC_DC Live");
+        assert_eq!(sanitizer.value("What?"), "What_");
+        assert_eq!(self::sanitizer(200, false).value("What?"), "What?");
+    }
+
+    #[test]
+    fn truncates_on_a_char_boundary() {
+        // "é" is two bytes, 16 bytes would split the eighth one
+        let name = "é".repeat(10);
+        assert_eq!(sanitizer(16, false).path(&name, None), "é".repeat(8));
+        assert_eq!(sanitizer(17, false).path(&name, None), "é".repeat(8));
+    }
+
+    #[test]
+    fn keeps_room_for_extension_and_part_suffix() {
+        let name = "a".repeat(50);
+        let path = sanitizer(30, false).path(&format!("Album/{}", name), Some("flac"));
+        let file_name = path.strip_prefix("Album/").unwrap();
+        assert_eq!(file_name, format!("{}.flac", "a".repeat(20)));
+        assert_eq!(format!("{}.part", file_name).len(), 30);
+    }
+
+    #[test]
+    fn reserved_windows_names() {
+        let sanitizer = sanitizer(200, true);
+        assert_eq!(sanitizer.path("CON", None), "CON_");
+        assert_eq!(sanitizer.path("con", Some("flac")), "con_.flac");
+        assert_eq!(sanitizer.path("Console", Some("flac")), "Console.flac");
+        assert_eq!(self::sanitizer(200, false).path("con", Some("flac")), "con.flac");
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces() {
+        assert_eq!(sanitizer(200, true).path("Vol. 1 . /Best of...", None), "Vol. 1/Best of");
+        assert_eq!(sanitizer(200, false).path("Best of...", None), "Best of...");
+    }
+
+    #[test]
+    fn dot_components() {
+        let sanitizer = sanitizer(200, false);
+        assert_eq!(sanitizer.path("../..", None), "_/_");
+        assert_eq!(sanitizer.path("Album/..", Some("flac")), "Album/_.flac");
+        assert_eq!(sanitizer.path("", None), "_");
+    }
+
+    #[test]
+    fn nfc_normalization() {
+        let sanitizer = sanitizer(200, false);
+        assert_eq!(sanitizer.value("Cafe\u{301}"), "Caf\u{e9}");
+        assert_eq!(sanitizer.path("Cafe\u{301}", None), "Caf\u{e9}");
+    }
+}
@@ -0,0 +1,211 @@
+// File and folder naming templates such as "{artist}/{year} - {album}".
+// `{name}` is replaced by a track field, `{name:02}` pads it with zeros to two characters
+// and `{{` / `}}` are literal braces.
+use crate::error::{Error, Result};
+
+pub const TRACK_FIELDS: &[&str] = &[
+    "artist",
+    "album_artist",
+    "album",
+    "year",
+    "track_number",
+    "disc_number",
+    "title",
+    "quality",
+    "explicit",
+    "track_id",
+];
+pub const PLAYLIST_FIELDS: &[&str] = &["playlist"];
+pub const VIDEO_FIELDS: &[&str] = &["artist", "title", "year", "quality", "explicit", "video_id"];
+
+#[derive(Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Literal(String),
+    Field {
+        name: String,
+        width: usize,
+        zero_pad: bool,
+    },
+}
+
+impl Template {
+    // `option` is the config key, only used to point at the culprit in error messages
+    pub fn parse(template: &str, option: &str, fields: &[&str]) -> Result<Template> {
+        let error = |position: usize, message: String| {
+            Error::Config(format!(
+                "{} \"{}\": {} at position {}",
+                option, template, message, position
+            ))
+        };
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.char_indices().peekable();
+        while let Some((position, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().map(|(_, c)| *c) == Some('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek().map(|(_, c)| *c) == Some('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => return Err(error(position, String::from("unmatched `}`"))),
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) => placeholder.push(c),
+                            None => return Err(error(position, String::from("unclosed `{`"))),
+                        }
+                    }
+                    let (name, format) = match placeholder.split_once(':') {
+                        Some((name, format)) => (name.trim(), Some(format.trim())),
+                        None => (placeholder.trim(), None),
+                    };
+                    if !fields.contains(&name) {
+                        return Err(error(
+                            position,
+                            format!(
+                                "unknown placeholder `{{{}}}` (expected one of {})",
+                                name,
+                                fields.join(", ")
+                            ),
+                        ));
+                    }
+                    let (width, zero_pad) = match format {
+                        Some(format) => match format.parse::<usize>() {
+                            Ok(width) => (width, format.starts_with('0')),
+                            Err(_) => {
+                                return Err(error(
+                                    position,
+                                    format!(
+                                        "invalid format `{}` for `{}`, expected a width such as 02",
+                                        format, name
+                                    ),
+                                ))
+                            }
+                        },
+                        None => (0, false),
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field {
+                        name: name.to_string(),
+                        width,
+                        zero_pad,
+                    });
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    pub fn has_field(&self, field: &str) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Field { name, .. } if name == field))
+    }
+
+    // Placeholders `value` knows nothing about render as an empty string
+    pub fn render(&self, value: &dyn Fn(&str) -> Option<String>) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => out.push_str(literal),
+                Part::Field {
+                    name,
+                    width,
+                    zero_pad,
+                } => {
+                    let value = value(name).unwrap_or_default();
+                    if *zero_pad {
+                        out.push_str(&format!("{:0>width$}", value, width = width));
+                    } else {
+                        out.push_str(&format!("{:width$}", value, width = width));
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str) -> String {
+        Template::parse(template, "track_template", TRACK_FIELDS)
+            .unwrap()
+            .render(&|name| match name {
+                "track_number" => Some(String::from("7")),
+                "title" => Some(String::from("Blue in Green")),
+                _ => None,
+            })
+    }
+
+    fn error(template: &str) -> String {
+        match Template::parse(template, "track_template", TRACK_FIELDS) {
+            Err(Error::Config(message)) => message,
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn padded_width() {
+        assert_eq!(render("{track_number:02} - {title}"), "07 - Blue in Green");
+        assert_eq!(render("{track_number:3}."), "7  .");
+        assert_eq!(render("{track_number:1}"), "7");
+    }
+
+    #[test]
+    fn escaped_braces() {
+        assert_eq!(render("{{{title}}}"), "{Blue in Green}");
+        assert_eq!(render("}}{{"), "}{");
+    }
+
+    #[test]
+    fn unknown_fields_render_empty() {
+        assert_eq!(render("{title} [{quality}]"), "Blue in Green []");
+    }
+
+    #[test]
+    fn unknown_placeholder() {
+        let message = error("{artist} - {name}");
+        assert!(message.contains("unknown placeholder `{name}`"), "{}", message);
+        assert!(message.contains("position 11"), "{}", message);
+        assert!(message.starts_with("track_template"), "{}", message);
+    }
+
+    #[test]
+    fn unclosed_brace() {
+        let message = error("{artist} - {title");
+        assert!(message.contains("unclosed `{`"), "{}", message);
+        assert!(message.contains("position 11"), "{}", message);
+    }
+
+    #[test]
+    fn stray_closing_brace() {
+        let message = error("{artist}} - {title}");
+        assert!(message.contains("unmatched `}`"), "{}", message);
+        assert!(message.contains("position 8"), "{}", message);
+    }
+
+    #[test]
+    fn invalid_width() {
+        let message = error("{track_number:two}");
+        assert!(message.contains("invalid format `two` for `track_number`"), "{}", message);
+    }
+}
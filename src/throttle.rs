@@ -0,0 +1,151 @@
+// Bandwidth limiting: a token bucket shared by every transfer of the run, so the limit
+// applies to the sum of all concurrent downloads. The limit may change with the time of day.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Timelike;
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+
+// Bytes per second, None for unlimited
+pub type Rate = Option<u64>;
+
+// (minute of the day the entry starts at, rate), sorted by start
+type Schedule = Vec<(u32, Rate)>;
+
+// How many seconds worth of the rate may be used at once after an idle period
+const BURST_SECONDS: f64 = 0.25;
+
+#[derive(Debug)]
+pub struct Throttle {
+    schedule: Schedule,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Rate,
+    // Negative when transfers have taken more than was available and have to wait it off
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    // max_bandwidth and bandwidth_schedule are checked by Config::validate
+    pub fn new(config: &Config) -> Throttle {
+        let schedule = parse_schedule(&config.bandwidth_schedule).unwrap_or_default();
+        if schedule.is_empty() {
+            Throttle::with_rate(parse_rate(&config.max_bandwidth).unwrap_or(None))
+        } else {
+            Throttle::with_schedule(schedule)
+        }
+    }
+
+    pub fn with_rate(rate: Rate) -> Throttle {
+        Throttle::with_schedule(vec![(0, rate)])
+    }
+
+    fn with_schedule(schedule: Schedule) -> Throttle {
+        Throttle {
+            schedule,
+            bucket: Mutex::new(Bucket {
+                rate: None,
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    // Account for `bytes` just received, waiting as long as they put the run over the limit
+    pub async fn consume(&self, bytes: usize) {
+        let rate = self.rate_now();
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let Some(rate) = rate else {
+                bucket.rate = None;
+                return;
+            };
+            let per_second = rate as f64;
+            let burst = per_second * BURST_SECONDS;
+            if bucket.rate != Some(rate) {
+                // The limit just came into effect or changed, start with a full bucket
+                bucket.rate = Some(rate);
+                bucket.tokens = burst;
+            } else {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+            }
+            bucket.updated = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / per_second)
+        };
+        tokio::time::sleep(wait).await;
+    }
+
+    fn rate_now(&self) -> Rate {
+        let now = chrono::Local::now();
+        let minute = now.hour() * 60 + now.minute();
+        // Before the first entry of the day the last one of the previous day still applies
+        self.schedule
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= minute)
+            .or(self.schedule.last())
+            .and_then(|(_, rate)| *rate)
+    }
+}
+
+// "0", "off" or "" for unlimited, otherwise bytes per second with an optional K, M or G
+// suffix (powers of 1024), e.g. "500K" or "2M"
+pub fn parse_rate(value: &str) -> Result<Rate> {
+    let value = value.trim();
+    if value.is_empty() || value.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+    let invalid = || Error::Config(format!("invalid bandwidth \"{}\"", value));
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, suffix)) if suffix.is_ascii_alphabetic() => (&value[..i], suffix),
+        _ => (value, 'B'),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase() {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    let number: f64 = number.trim().parse().map_err(|_| invalid())?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(invalid());
+    }
+    match (number * multiplier as f64) as u64 {
+        0 => Ok(None),
+        rate => Ok(Some(rate)),
+    }
+}
+
+// Space separated "HH:MM,RATE" entries, each rate applies from its time until the next
+// entry's, e.g. "08:00,1M 19:00,off"
+pub fn parse_schedule(value: &str) -> Result<Schedule> {
+    let mut schedule = Vec::new();
+    for entry in value.split_whitespace() {
+        let invalid = || Error::Config(format!("invalid bandwidth_schedule entry \"{}\"", entry));
+        let (time, rate) = entry.split_once(',').ok_or_else(invalid)?;
+        let (hour, minute) = time.split_once(':').ok_or_else(invalid)?;
+        let hour: u32 = hour.parse().map_err(|_| invalid())?;
+        let minute: u32 = minute.parse().map_err(|_| invalid())?;
+        if hour > 23 || minute > 59 {
+            return Err(invalid());
+        }
+        schedule.push((hour * 60 + minute, parse_rate(rate)?));
+    }
+    schedule.sort_by_key(|(start, _)| *start);
+    Ok(schedule)
+}
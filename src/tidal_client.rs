@@ -1,30 +1,86 @@
-use std::{fs, path::Path, process::exit, thread, time::Duration};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use crate::archive::Archive;
 use crate::config::*;
+use crate::error::{Error, Result};
+use crate::models::{Page, TokenResponse, TokenResult};
+use crate::retry::{self, RetryPolicy};
+use crate::throttle::Throttle;
+use base64::Engine;
+use indicatif::MultiProgress;
 use reqwest::{
     header::{HeaderMap, HeaderValue},
-    Client, Response,
+    Client, Response, StatusCode,
 };
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::{json, Value};
+use tokio::sync::Semaphore;
+
+use crate::constants::{
+    TIDAL_AUTH_LINK, TIDAL_BASE, TIDAL_CLIENT_INFO_ID, TIDAL_CLIENT_INFO_SECRET,
+};
 
-use crate::constants::{TIDAL_AUTH_LINK, TIDAL_BASE, USER_AGENT};
+// Refresh the access token this many seconds before it actually expires
+const TOKEN_EXPIRY_MARGIN: u64 = 60;
+// Largest page size TIDAL accepts for listing endpoints
+const PAGE_LIMIT: usize = 100;
 
 #[derive(Debug)]
 pub struct TidalClient {
     pub device_code: DeviceCode,
-    pub user_info: UserInfo,
+    pub user_info: Mutex<UserInfo>,
     pub config: Config,
     pub have_userinfo: bool,
+    // Shared by every url of the run: API requests and stream transfers in flight
+    pub api_slots: Semaphore,
+    pub download_slots: Arc<Semaphore>,
+    // Tracks resolved ahead of the transfers, across all urls, since stream urls expire
+    // while they wait for a download slot
+    pub resolve_slots: Semaphore,
+    pub progress: MultiProgress,
+    pub throttle: Arc<Throttle>,
+    // Opened by `open_archive` when download_archive is set
+    pub archive: Option<Archive>,
+    // Files being written right now. Urls run side by side, and a track may be listed by two
+    // of them (or twice by a playlist), exist_check alone can't keep them from both writing it.
+    in_flight: Mutex<HashSet<PathBuf>>,
+    // Concurrent requests finding the token expired refresh it only once
+    refresh_lock: tokio::sync::Mutex<()>,
 }
 #[derive(Debug, Default)]
 pub struct UserInfo {
     pub access_token: String,
     pub expires_in: String,
+    // Unix timestamp (seconds) at which `access_token` expires, 0 if unknown
+    pub expires_at: u64,
     pub country_code: String,
     pub user_id: String,
     pub refresh_token: String,
 }
-#[derive(Debug, Default)]
+impl UserInfo {
+    fn update(&mut self, token: TokenResponse) {
+        self.access_token = token.access_token;
+        self.expires_in = token.expires_in.to_string();
+        self.expires_at = unix_now() + token.expires_in;
+        // TIDAL usually keeps the old refresh token on refresh, but rotate it if a new one is given
+        if let Some(refresh_token) = token.refresh_token {
+            self.refresh_token = refresh_token;
+        }
+        if let Some(user) = token.user {
+            self.user_id = user.user_id.to_string();
+            self.country_code = user.country_code;
+        }
+    }
+}
+#[derive(Debug, Default, serde_derive::Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct DeviceCode {
     pub device_code: String,
     pub expires_in: usize,
@@ -34,260 +90,428 @@ pub struct DeviceCode {
 
 impl TidalClient {
     pub fn new(user_info: Option<(UserInfo, Config)>) -> TidalClient {
-        match user_info {
-            Some((user_info)) => TidalClient {
-                device_code: DeviceCode::default(),
-                user_info: user_info.0,
-                have_userinfo: true,
-                config: user_info.1,
-            },
-            None => TidalClient {
-                device_code: DeviceCode::default(),
-                user_info: UserInfo::default(),
-                have_userinfo: false,
-                config: Config::new(),
-            },
+        let (user_info, config, have_userinfo) = match user_info {
+            Some((user_info, config)) => (user_info, config, true),
+            None => (UserInfo::default(), Config::new(), false),
+        };
+        TidalClient {
+            device_code: DeviceCode::default(),
+            user_info: Mutex::new(user_info),
+            have_userinfo,
+            api_slots: Semaphore::new(config.max_api_requests),
+            download_slots: Arc::new(Semaphore::new(config.max_downloads)),
+            resolve_slots: Semaphore::new(config.max_downloads),
+            progress: MultiProgress::new(),
+            throttle: Arc::new(Throttle::new(&config)),
+            archive: None,
+            in_flight: Mutex::new(HashSet::new()),
+            refresh_lock: tokio::sync::Mutex::new(()),
+            config,
         }
     }
 
     //pub async fn login() {}
 
     //id == client_id
-    pub async fn get_session(&mut self, id: String) {
+    pub async fn get_session(&mut self, id: String) -> Result<()> {
         if self.have_userinfo {
             println!("Session exists!\nSkip login..");
             println!("{:?}", self);
-            return;
+            return Ok(());
         }
-        let mut header: HeaderMap = HeaderMap::new();
-        header.insert(
-            "Content-Type",
-            HeaderValue::from_static("application/x-www-form-urlencoded"),
-        );
         let payload = json!({
             "client_id": id.clone(),
             "scope": "r_usr+w_usr+w_sub",
         });
         let payload = serde_urlencoded::to_string(&payload).unwrap();
-        let client = reqwest::Client::builder()
-            .user_agent(USER_AGENT)
-            .build()
-            .unwrap();
+        let client = retry::client(&self.config)?;
         let device_auth_url = format!("{}/device_authorization", TIDAL_AUTH_LINK);
         let response = self
             .api_post(client.clone(), device_auth_url, payload)
-            .await;
-        let val = response.json::<serde_json::Value>().await.unwrap();
-        self.device_code = DeviceCode {
-            device_code: remove_non_alphanumeric((*val.get("deviceCode").unwrap()).to_string()),
-            expires_in: (*val.get("expiresIn").unwrap())
-                .to_string()
-                .parse::<usize>()
-                .unwrap(),
-            interval: (*val.get("interval").unwrap())
-                .to_string()
-                .parse::<usize>()
-                .unwrap(),
-            user_code: remove_non_alphanumeric((*val.get("userCode").unwrap()).to_string()),
-        };
+            .await?;
+        self.device_code = response.json::<DeviceCode>().await?;
         let payload_d = json!({
-            "client_id": "zU4XHVVkc2tDPo4t",
+            "client_id": id,
             "device_code": self.device_code.device_code,
             "grant_type": "urn:ietf:params:oauth:grant-type:device_code",
             "scope": "r_usr+w_usr+w_sub",
         });
         let payload_d2 = serde_urlencoded::to_string(&payload_d).unwrap();
         let mut elapsed = 0;
+        let expires_in = self.device_code.expires_in;
+        let interval = self.device_code.interval.max(1);
         println!(
-            "connect via https://link.tidal.com/{}\nplease login before 300s",
-            self.device_code.user_code
+            "connect via https://link.tidal.com/{}\nplease login before {}s",
+            self.device_code.user_code, expires_in
         );
-        while elapsed < 300 {
+        while elapsed < expires_in {
             let a = self
                 .check_auth_token(client.clone(), payload_d2.clone())
-                .await;
+                .await?;
             if a != 200 {
-                thread::sleep(Duration::from_millis(1000));
-                elapsed += 1;
+                tokio::time::sleep(Duration::from_secs(interval as u64)).await;
+                elapsed += interval;
                 continue;
             } else {
                 println!("Connected Tidal successfully");
                 println!("{:?}", self);
-                break;
+                return Ok(());
             }
         }
-        if elapsed >= 300 {
-            println!("timeout!\nretry later");
-            exit(0);
-        }
-
-        // println!("{:?}", response.status());
+        Err(Error::Auth(String::from(
+            "device login timed out, retry later",
+        )))
     }
-    async fn api_post(&self, client: Client, url: String, data: String) -> Response {
-        let response = client
+    async fn api_post(&self, client: Client, url: String, data: String) -> Result<Response> {
+        let request = client
             .post(url)
             .header("Content-Type", "application/x-www-form-urlencoded")
-            .body(data)
-            .send()
-            .await
-            .unwrap();
-        response
+            .body(data);
+        RetryPolicy::new(&self.config).send(request).await
     }
 
-    async fn check_auth_token(&mut self, client: Client, data: String) -> usize {
+    async fn check_auth_token(&mut self, client: Client, data: String) -> Result<usize> {
         let auth_token_url = format!("{}/token", TIDAL_AUTH_LINK);
-        let response = self.api_post(client, auth_token_url, data).await;
-        let tmp = response.json::<serde_json::Value>().await.unwrap();
-        match tmp.get("status") {
-            Some(status) => return status.to_string().parse::<usize>().unwrap(),
-            None => {
-                self.user_info.access_token =
-                    remove_non_alphanumeric((tmp.get("access_token").unwrap()).to_string());
-                self.user_info.country_code = remove_non_alphanumeric(
-                    tmp.get("user")
-                        .unwrap()
-                        .get("countryCode")
-                        .unwrap()
-                        .to_string(),
-                );
-                self.user_info.expires_in =
-                    remove_non_alphanumeric((tmp.get("expires_in").unwrap()).to_string());
-                self.user_info.user_id = remove_non_alphanumeric(
-                    (tmp.get("user").unwrap().get("userId").unwrap()).to_string(),
-                );
-                self.user_info.refresh_token =
-                    remove_non_alphanumeric((tmp.get("refresh_token").unwrap()).to_string());
-                return 200;
+        let response = self.api_post(client, auth_token_url, data).await?;
+        match response.json::<TokenResult>().await? {
+            TokenResult::Error(error) => Ok(error.status as usize),
+            TokenResult::Token(token) => {
+                self.user_info.get_mut().unwrap().update(token);
+                Ok(200)
             }
         }
     }
 
-    pub async fn login_session(&self) {
-        let mut header: HeaderMap = HeaderMap::new();
-        let token = format!("Bearer {}", self.user_info.access_token);
-        header.insert(
-            "authorization",
-            HeaderValue::from_str(token.as_str()).unwrap(),
-        );
-        let client = reqwest::Client::builder()
-            .user_agent(USER_AGENT)
-            .build()
-            .unwrap();
-        client
-            .get(format!("{}/sessions", TIDAL_BASE))
-            .headers(header)
-            .send()
-            .await
-            .unwrap();
+    pub fn is_token_expired(&self) -> bool {
+        let expires_at = self.user_info.lock().unwrap().expires_at;
+        unix_now() + TOKEN_EXPIRY_MARGIN >= expires_at
+    }
+
+    // Exchange the stored refresh token for a new access token and rewrite .tdlrs.json
+    pub async fn refresh_access_token(&self) -> Result<()> {
+        let refresh_token = self.user_info.lock().unwrap().refresh_token.clone();
+        if refresh_token.is_empty() {
+            return Err(Error::Auth(String::from("no refresh token stored")));
+        }
+        let client_id = decode_client_info(TIDAL_CLIENT_INFO_ID);
+        let client_secret = decode_client_info(TIDAL_CLIENT_INFO_SECRET);
+        let payload = json!({
+            "client_id": client_id,
+            "refresh_token": refresh_token,
+            "grant_type": "refresh_token",
+            "scope": "r_usr+w_usr+w_sub",
+        });
+        let payload = serde_urlencoded::to_string(&payload).unwrap();
+        let client = retry::client(&self.config)?;
+        let request = client
+            .post(format!("{}/token", TIDAL_AUTH_LINK))
+            .basic_auth(client_id, Some(client_secret))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(payload);
+        let response = RetryPolicy::new(&self.config).send(request).await?;
+        match response.json::<TokenResult>().await? {
+            TokenResult::Token(token) => {
+                self.user_info.lock().unwrap().update(token);
+                self.save_token().await
+            }
+            TokenResult::Error(error) => Err(Error::Auth(format!(
+                "refreshing the session token failed ({}: {})",
+                error.status,
+                error.error.unwrap_or_default()
+            ))),
+        }
+    }
+
+    // Refresh the access token unless another request already replaced `stale_token`
+    async fn refresh_token_once(&self, stale_token: &str) -> Result<()> {
+        let _lock = self.refresh_lock.lock().await;
+        if self.access_token() != stale_token {
+            return Ok(());
+        }
+        self.refresh_access_token().await
+    }
+
+    fn access_token(&self) -> String {
+        self.user_info.lock().unwrap().access_token.clone()
+    }
+
+    pub fn open_archive(&mut self) -> Result<()> {
+        if !self.config.download_archive.is_empty() {
+            self.archive = Some(Archive::open(Path::new(&self.config.download_archive))?);
+        }
+        Ok(())
+    }
+
+    // Whether `path` is free to be written, false while another download writes it. A
+    // successful claim ends with `release_path`.
+    pub fn claim_path(&self, path: &Path) -> bool {
+        self.in_flight.lock().unwrap().insert(path.to_path_buf())
     }
 
-    pub async fn save_token(&self) {
-        let tdl_token_exist = Path::new(".tdlrs.json").exists();
-        if !tdl_token_exist {
-            fs::File::create(".tdlrs.json").expect("failed to create file");
+    pub fn release_path(&self, path: &Path) {
+        self.in_flight.lock().unwrap().remove(path);
+    }
+
+    pub fn country_code(&self) -> String {
+        self.user_info.lock().unwrap().country_code.clone()
+    }
+
+    // GET an authorized TIDAL API url, refreshing the access token when it has expired
+    // or when the server answers 401
+    pub async fn api_get<T: Serialize + ?Sized>(
+        &self,
+        client: &Client,
+        url: &str,
+        params: &T,
+    ) -> Result<Response> {
+        if self.is_token_expired() {
+            self.refresh_token_once(&self.access_token()).await?;
+        }
+        let retry = RetryPolicy::new(&self.config);
+        let send = |token: &str| {
+            let request = client.get(url).query(params).headers(auth_header(token));
+            async {
+                let _slot = self.api_slots.acquire().await.expect("api_slots is never closed");
+                retry.send(request).await
+            }
+        };
+        let token = self.access_token();
+        let mut response = send(&token).await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            self.refresh_token_once(&token).await?;
+            response = send(&self.access_token()).await?;
+        }
+        match response.status() {
+            StatusCode::UNAUTHORIZED => Err(Error::Auth(format!("access to {} was denied", url))),
+            status if !status.is_success() => Err(Error::Http {
+                status,
+                url: url.to_string(),
+            }),
+            _ => Ok(response),
+        }
+    }
+
+    // GET every item of a paginated TIDAL listing (`items` + `totalNumberOfItems`)
+    // by following `offset` until all of them are retrieved. A page may hold fewer items than
+    // asked for (e.g. unavailable in the country), the next one still starts a full page later.
+    pub async fn api_get_all<T: DeserializeOwned>(
+        &self,
+        client: &Client,
+        url: &str,
+        extra_params: &[(&str, &str)],
+    ) -> Result<Vec<T>> {
+        let country_code = self.country_code();
+        let limit = PAGE_LIMIT.to_string();
+        let mut items: Vec<T> = Vec::new();
+        let mut offset = 0;
+        loop {
+            let offset_param = offset.to_string();
+            let mut params = vec![
+                ("countryCode", country_code.as_str()),
+                ("limit", limit.as_str()),
+                ("offset", offset_param.as_str()),
+            ];
+            params.extend_from_slice(extra_params);
+            let response = self.api_get(client, url, &params).await?;
+            let page = response.json::<Page<T>>().await?;
+            items.extend(page.items);
+            offset += PAGE_LIMIT;
+            if offset >= page.total_number_of_items {
+                break;
+            }
         }
+        Ok(items)
+    }
+
+    pub async fn login_session(&self) -> Result<()> {
+        let client = retry::client(&self.config)?;
+        self.api_get(&client, &format!("{}/sessions", TIDAL_BASE), &())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn save_token(&self) -> Result<()> {
         let file = std::fs::OpenOptions::new()
             .create(true)
             .write(true)
             .read(true)
-            .append(false)
-            .open(".tdlrs.json")
-            .expect("failed to read file");
+            .truncate(true)
+            .open(".tdlrs.json")?;
+        let user_info = self.user_info.lock().unwrap();
         // TODO: Implement enum to some T to write json properly
         let json = json!({
-            "access_token": self.user_info.access_token,
-            "expires_in": self.user_info.expires_in,
-            "country_code": self.user_info.country_code,
-            "user_id": self.user_info.user_id,
-            "refresh_token": self.user_info.refresh_token,
+            "access_token": user_info.access_token,
+            "expires_in": user_info.expires_in,
+            "expires_at": user_info.expires_at.to_string(),
+            "country_code": user_info.country_code,
+            "user_id": user_info.user_id,
+            "refresh_token": user_info.refresh_token,
             "download_path": self.config.download_path,
             "audio_quality": self.config.audio_quality,
             "save_cover": self.config.save_cover.to_string(),
+            "embed_cover": self.config.embed_cover.to_string(),
+            "embed_cover_size": self.config.embed_cover_size,
+            "folder_cover_size": self.config.folder_cover_size,
             "exist_check": self.config.exist_check.to_string(),
+            "artist_albums": self.config.artist_albums.to_string(),
+            "artist_eps_singles": self.config.artist_eps_singles.to_string(),
+            "artist_compilations": self.config.artist_compilations.to_string(),
+            "album_template": self.config.album_template,
+            "playlist_template": self.config.playlist_template,
+            "track_template": self.config.track_template,
+            "video_template": self.config.video_template,
+            "video_resolution": self.config.video_resolution.to_string(),
+            "download_videos": self.config.download_videos.to_string(),
+            "replacement_char": self.config.replacement_char,
+            "max_name_length": self.config.max_name_length.to_string(),
+            "windows_compatible": self.config.windows_compatible.to_string(),
+            "disc_folders": self.config.disc_folders.to_string(),
+            "replay_gain": self.config.replay_gain.to_string(),
+            "loudness_analysis": self.config.loudness_analysis.to_string(),
+            "embed_lyrics": self.config.embed_lyrics.to_string(),
+            "save_lrc": self.config.save_lrc.to_string(),
+            "credits": self.config.credits.to_string(),
+            "retry_attempts": self.config.retry_attempts.to_string(),
+            "retry_delay_ms": self.config.retry_delay_ms.to_string(),
+            "retry_max_delay_ms": self.config.retry_max_delay_ms.to_string(),
+            "connect_timeout_ms": self.config.connect_timeout_ms.to_string(),
+            "response_timeout_ms": self.config.response_timeout_ms.to_string(),
+            "max_downloads": self.config.max_downloads.to_string(),
+            "max_api_requests": self.config.max_api_requests.to_string(),
+            "max_bandwidth": self.config.max_bandwidth,
+            "bandwidth_schedule": self.config.bandwidth_schedule,
+            "download_archive": self.config.download_archive,
         });
 
-        serde_json::to_writer_pretty(&file, &json).unwrap();
+        serde_json::to_writer_pretty(&file, &json)
+            .map_err(|e| Error::Io(std::io::Error::other(e)))
     }
 }
-pub async fn get_token() -> Option<(UserInfo, Config)> {
+pub async fn get_token() -> Result<Option<(UserInfo, Config)>> {
     let exist = Path::new(".tdlrs.json").exists();
     if !exist {
-        //fs::File::create(".tdlrs.json").expect("failed to create file");
-        return None;
+        return Ok(None);
     }
-    //let file = fs::File::open(".tdlrs.json").unwrap();
-    let json: Value = serde_json::from_str(
-        fs::read_to_string(".tdlrs.json")
-            .expect("cannot open .tdlrs.json!")
-            .as_str(),
-    )
-    .expect("cannot parse json");
+    let json: Value = serde_json::from_str(fs::read_to_string(".tdlrs.json")?.as_str())
+        .map_err(|e| Error::Config(format!("cannot parse .tdlrs.json: {}", e)))?;
     let user_info = UserInfo {
-        access_token: json
-            .get("access_token")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string(),
-        expires_in: json
-            .get("expires_in")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string(),
-        country_code: json
-            .get("country_code")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string(),
-        user_id: json.get("user_id").unwrap().as_str().unwrap().to_string(),
-        refresh_token: json
-            .get("refresh_token")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string(),
+        access_token: get_str(&json, "access_token")?,
+        expires_in: get_str(&json, "expires_in")?,
+        // Token files written before expiry tracking have no `expires_at`,
+        // treat them as expired so the token gets refreshed once
+        expires_at: json
+            .get("expires_at")
+            .and_then(|v| v.as_str())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or(0),
+        country_code: get_str(&json, "country_code")?,
+        user_id: get_str(&json, "user_id")?,
+        refresh_token: get_str(&json, "refresh_token")?,
     };
+    let defaults = Config::new();
     let config = Config {
-        download_path: json
-            .get("download_path")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string(),
-        audio_quality: json
-            .get("audio_quality")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string()
+        download_path: get_str(&json, "download_path")?,
+        audio_quality: get_str(&json, "audio_quality")?
             .parse::<AudioQuality>()
-            .unwrap(),
-        save_cover: json
-            .get("save_cover")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .trim()
-            .parse::<bool>()
-            .unwrap(),
-        exist_check: json
-            .get("exist_check")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string()
-            .trim()
-            .parse::<bool>()
-            .unwrap(),
+            .map_err(Error::Config)?,
+        save_cover: get_parsed(&json, "save_cover", defaults.save_cover)?,
+        embed_cover: get_parsed(&json, "embed_cover", defaults.embed_cover)?,
+        embed_cover_size: get_string(&json, "embed_cover_size", defaults.embed_cover_size)?,
+        folder_cover_size: get_string(&json, "folder_cover_size", defaults.folder_cover_size)?,
+        exist_check: get_parsed(&json, "exist_check", defaults.exist_check)?,
+        artist_albums: get_parsed(&json, "artist_albums", defaults.artist_albums)?,
+        artist_eps_singles: get_parsed(&json, "artist_eps_singles", defaults.artist_eps_singles)?,
+        artist_compilations: get_parsed(
+            &json,
+            "artist_compilations",
+            defaults.artist_compilations,
+        )?,
+        album_template: get_string(&json, "album_template", defaults.album_template)?,
+        playlist_template: get_string(&json, "playlist_template", defaults.playlist_template)?,
+        track_template: get_string(&json, "track_template", defaults.track_template)?,
+        video_template: get_string(&json, "video_template", defaults.video_template)?,
+        video_resolution: get_parsed(&json, "video_resolution", defaults.video_resolution)?,
+        download_videos: get_parsed(&json, "download_videos", defaults.download_videos)?,
+        replacement_char: get_string(&json, "replacement_char", defaults.replacement_char)?,
+        max_name_length: get_parsed(&json, "max_name_length", defaults.max_name_length)?,
+        windows_compatible: get_parsed(&json, "windows_compatible", defaults.windows_compatible)?,
+        disc_folders: get_parsed(&json, "disc_folders", defaults.disc_folders)?,
+        replay_gain: get_parsed(&json, "replay_gain", defaults.replay_gain)?,
+        loudness_analysis: get_parsed(&json, "loudness_analysis", defaults.loudness_analysis)?,
+        embed_lyrics: get_parsed(&json, "embed_lyrics", defaults.embed_lyrics)?,
+        save_lrc: get_parsed(&json, "save_lrc", defaults.save_lrc)?,
+        credits: get_parsed(&json, "credits", defaults.credits)?,
+        retry_attempts: get_parsed(&json, "retry_attempts", defaults.retry_attempts)?,
+        retry_delay_ms: get_parsed(&json, "retry_delay_ms", defaults.retry_delay_ms)?,
+        retry_max_delay_ms: get_parsed(&json, "retry_max_delay_ms", defaults.retry_max_delay_ms)?,
+        connect_timeout_ms: get_parsed(&json, "connect_timeout_ms", defaults.connect_timeout_ms)?,
+        response_timeout_ms: get_parsed(
+            &json,
+            "response_timeout_ms",
+            defaults.response_timeout_ms,
+        )?,
+        max_downloads: get_parsed(&json, "max_downloads", defaults.max_downloads)?,
+        max_api_requests: get_parsed(&json, "max_api_requests", defaults.max_api_requests)?,
+        max_bandwidth: get_string(&json, "max_bandwidth", defaults.max_bandwidth)?,
+        bandwidth_schedule: get_string(&json, "bandwidth_schedule", defaults.bandwidth_schedule)?,
+        download_archive: get_string(&json, "download_archive", defaults.download_archive)?,
     };
-    Some((user_info, config))
+    config.validate()?;
+    Ok(Some((user_info, config)))
+}
+
+fn get_str(json: &Value, key: &str) -> Result<String> {
+    json.get(key)
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string())
+        .ok_or_else(|| Error::Config(format!("`{}` is missing from .tdlrs.json", key)))
+}
+
+// Options added after the first release may be missing from older .tdlrs.json files. A value
+// that is present has to be valid, falling back to the default would make save_token overwrite
+// it. Numbers and booleans may also be written without quotes.
+fn get_parsed<T: FromStr>(json: &Value, key: &str, default: T) -> Result<T> {
+    match get_value(json, key)? {
+        Some(value) => value
+            .trim()
+            .parse::<T>()
+            .map_err(|_| Error::Config(format!("invalid `{}` in .tdlrs.json: {}", key, value))),
+        None => Ok(default),
+    }
+}
+
+fn get_string(json: &Value, key: &str, default: String) -> Result<String> {
+    Ok(get_value(json, key)?.unwrap_or(default))
+}
+
+fn get_value(json: &Value, key: &str) -> Result<Option<String>> {
+    match json.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        Some(value @ (Value::Number(_) | Value::Bool(_))) => Ok(Some(value.to_string())),
+        Some(value) => Err(Error::Config(format!(
+            "invalid `{}` in .tdlrs.json: {}",
+            key, value
+        ))),
+    }
+}
+
+fn auth_header(access_token: &str) -> HeaderMap {
+    let mut header: HeaderMap = HeaderMap::new();
+    let token = format!("Bearer {}", access_token);
+    if let Ok(token) = HeaderValue::from_str(token.as_str()) {
+        header.insert("authorization", token);
+    }
+    header
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
 
-pub fn remove_non_alphanumeric(s: String) -> String {
-    s.replace("\"", "")
-        .replace("\\", "")
-        .replace("[", "")
-        .replace("]", "")
+fn decode_client_info(encoded: &str) -> String {
+    String::from_utf8(base64::prelude::BASE64_STANDARD.decode(encoded).unwrap()).unwrap()
 }
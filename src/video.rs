@@ -0,0 +1,189 @@
+// Music videos: TIDAL serves them as HLS, whose segments are concatenated into a single
+// .ts file, or .mp4 for fragmented MP4 segments.
+use std::{fs, path::Path};
+
+use base64::Engine;
+use indicatif::ProgressBar;
+use reqwest::Client;
+
+use crate::{
+    constants::TIDAL_BASE,
+    download::{part_path, transfer_style, write_stream},
+    error::{Error, Result},
+    hls,
+    models::{Manifest, Video, VideoPlaybackInfo},
+    retry::RetryPolicy,
+    sanitize::Sanitizer,
+    template::{Template, VIDEO_FIELDS},
+    tidal_client::TidalClient,
+};
+
+pub async fn get_video(t_client: &TidalClient, r_client: &Client, video_id: u32) -> Result<Video> {
+    let country_code = t_client.country_code();
+    let params = [("countryCode", country_code.as_str())];
+    let url = format!("{}/videos/{}", TIDAL_BASE, video_id);
+    Ok(t_client
+        .api_get(r_client, &url, &params)
+        .await?
+        .json::<Video>()
+        .await?)
+}
+
+// Videos of an artist, for discography downloads with download_videos
+pub async fn get_artist_videos(
+    t_client: &TidalClient,
+    r_client: &Client,
+    artist_id: u32,
+) -> Result<Vec<Video>> {
+    let url = format!("{}/artists/{}/videos", TIDAL_BASE, artist_id);
+    t_client.api_get_all(r_client, &url, &[]).await
+}
+
+// Download `videos` one after another, returning the failed ones
+pub async fn download_videos(
+    t_client: &TidalClient,
+    r_client: &Client,
+    videos: &[Video],
+    dir: &str,
+) -> Vec<(String, Error)> {
+    let mut failed = Vec::new();
+    for video in videos {
+        if let Err(e) = download_video(t_client, r_client, video, dir).await {
+            let _ = t_client.progress.println(format!("{}: {}", video.title, e));
+            failed.push((video.title.clone(), e));
+        }
+    }
+    failed
+}
+
+// `dir` is the folder video_template is relative to: download_path for video urls, the
+// album or artist folder for videos downloaded along with an album or discography
+pub async fn download_video(
+    t_client: &TidalClient,
+    r_client: &Client,
+    video: &Video,
+    dir: &str,
+) -> Result<()> {
+    let url = format!("{}/videos/{}/playbackinfopostpaywall", TIDAL_BASE, video.id);
+    let params = [
+        ("videoquality", "HIGH"),
+        ("playbackmode", "STREAM"),
+        ("assetpresentation", "FULL"),
+    ];
+    let info = t_client
+        .api_get(r_client, &url, &params)
+        .await?
+        .json::<VideoPlaybackInfo>()
+        .await?;
+    if info.manifest_mime_type != "application/vnd.tidal.emu" {
+        return Err(Error::Unsupported(format!(
+            "video manifest type {}",
+            info.manifest_mime_type
+        )));
+    }
+    let manifest = base64::prelude::BASE64_STANDARD
+        .decode(info.manifest)
+        .map_err(|e| Error::Manifest(e.to_string()))?;
+    let manifest: Manifest =
+        serde_json::from_slice(&manifest).map_err(|e| Error::Manifest(e.to_string()))?;
+    let master_url = manifest
+        .urls
+        .first()
+        .ok_or_else(|| Error::Manifest(String::from("no stream url")))?;
+
+    let config = &t_client.config;
+    let retry = RetryPolicy::new(config);
+    let master = get_playlist(r_client, &retry, master_url).await?;
+    let variants = hls::parse_master(&master, master_url)?;
+    let variant = hls::select_variant(&variants, config.video_resolution)
+        .ok_or_else(|| Error::Manifest(String::from("no variant in HLS master playlist")))?;
+    let media = get_playlist(r_client, &retry, &variant.url).await?;
+    let media = hls::parse_media(&media, &variant.url)?;
+
+    let quality = match variant.resolution {
+        Some((_, height)) => format!("{}p", height),
+        None => info.video_quality.or(video.quality.clone()).unwrap_or_default(),
+    };
+    let sanitizer = Sanitizer::new(config);
+    let template = Template::parse(&config.video_template, "video_template", VIDEO_FIELDS)?;
+    let year = video
+        .release_date
+        .as_ref()
+        .or(video.stream_start_date.as_ref())
+        .and_then(|date| date.get(..4))
+        .map(|year| year.to_string());
+    let rendered = template.render(&|name| {
+        let value = match name {
+            "artist" => Some(video.artist_name()),
+            "title" => Some(video.title.clone()),
+            "year" => year.clone(),
+            "quality" => Some(quality.clone()),
+            "explicit" => Some(if video.explicit { "E" } else { "" }.to_string()),
+            "video_id" => Some(video.id.to_string()),
+            _ => None,
+        };
+        value.map(|value| sanitizer.value(&value))
+    });
+    let extension = if media.fmp4 { "mp4" } else { "ts" };
+    let file_name = sanitizer.path(&rendered, Some(extension));
+    let dl_path = format!("{}/{}", dir, file_name);
+    if !t_client.claim_path(Path::new(&dl_path)) {
+        let _ = t_client.progress.println(format!(
+            "{}: being downloaded for another url, skipped",
+            video.title
+        ));
+        return Ok(());
+    }
+    let result = write_video(t_client, r_client, &retry, media.urls, &file_name, &dl_path).await;
+    t_client.release_path(Path::new(&dl_path));
+    result
+}
+
+// Fetch the segments into `dl_path` once a download slot is free
+async fn write_video(
+    t_client: &TidalClient,
+    r_client: &Client,
+    retry: &RetryPolicy,
+    segments: Vec<String>,
+    file_name: &str,
+    dl_path: &str,
+) -> Result<()> {
+    let config = &t_client.config;
+    let _slot = t_client
+        .download_slots
+        .acquire()
+        .await
+        .expect("download_slots is never closed");
+    let pb = t_client.progress.add(ProgressBar::new(0));
+    pb.set_message(file_name.to_string());
+    if config.exist_check && Path::new(dl_path).exists() {
+        pb.finish_with_message("File already exists! Skip downloading..");
+        return Ok(());
+    }
+    pb.set_style(transfer_style());
+    if let Some(parent) = Path::new(dl_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let part_path = part_path(dl_path);
+    let result = match write_stream(r_client, retry, &t_client.throttle, &segments, None, &part_path, &pb).await {
+        Ok(()) => fs::rename(&part_path, dl_path).map_err(Error::from),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        pb.abandon_with_message(format!("Failed: {}", e));
+        return Err(e);
+    }
+    pb.finish_with_message("Downloaded");
+    Ok(())
+}
+
+async fn get_playlist(client: &Client, retry: &RetryPolicy, url: &str) -> Result<String> {
+    let resp = retry.send(client.get(url)).await?;
+    if !resp.status().is_success() {
+        return Err(Error::Http {
+            status: resp.status(),
+            url: url.to_string(),
+        });
+    }
+    Ok(resp.text().await?)
+}